@@ -2,13 +2,11 @@
 
 set -e
 
-RUSTFLAGS="-C link-args=-Tuser.ld -C linker=rust-lld" \
+RUSTFLAGS="-C link-args=-Tuser.ld -C linker=rust-lld -C relocation-model=pie -C link-arg=-pie" \
     cargo build --bin shell --target riscv64gc-unknown-none-elf
 
-cp ./target/riscv64gc-unknown-none-elf/debug/shell ./shell.elf
-
-llvm-objcopy --set-section-flags .bss=alloc,contents -O binary \
-      ./target/riscv64gc-unknown-none-elf/debug/shell shell.bin
+# The kernel loads the shell's segments itself, it doesn't need the debug info
+llvm-objcopy --strip-debug ./target/riscv64gc-unknown-none-elf/debug/shell shell.elf
 
 RUSTFLAGS="-C link-args=-Tos.ld -C linker=rust-lld" \
     cargo build --bin kernel --target riscv64gc-unknown-none-elf
@@ -5,6 +5,102 @@ pub enum Syscall {
     PUTCHAR,
     GETCHAR,
     EXIT,
+    SBRK,
+    MMAP,
+    MUNMAP,
+    MPROTECT,
+    SHMOPEN,
+    SHMMAP,
+    SHMUNMAP,
+    SHMDESTROY,
+    FORK,
+    VMMAP,
+    MEMINFO,
+}
+
+/// Pages of the mapping can be read
+pub const PROT_READ: u64 = 1 << 0;
+/// Pages of the mapping can be written, which implies they can be read too
+pub const PROT_WRITE: u64 = 1 << 1;
+/// Pages of the mapping can be executed
+pub const PROT_EXEC: u64 = 1 << 2;
+
+/// Map zero-filled memory rather than a file
+pub const MAP_ANONYMOUS: u64 = 1 << 0;
+/// Map at exactly the given address instead of picking one
+pub const MAP_FIXED: u64 = 1 << 1;
+
+/// The pages of a mapping can be read
+pub const MAPPING_READ: u64 = 1 << 0;
+/// The pages of a mapping can be written
+pub const MAPPING_WRITE: u64 = 1 << 1;
+/// The pages of a mapping can be executed
+pub const MAPPING_EXEC: u64 = 1 << 2;
+/// The pages of a mapping are accessible from user mode
+pub const MAPPING_USER: u64 = 1 << 3;
+/// The mapping is present in every address space
+pub const MAPPING_GLOBAL: u64 = 1 << 4;
+/// The pages of a mapping are shared copy-on-write, and become writable once copied
+pub const MAPPING_COW: u64 = 1 << 5;
+
+/// A range of virtual memory whose pages are all mapped with the same flags,
+/// as reported by the `VMMAP` syscall
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Mapping {
+    pub start: u64,
+    pub end: u64,
+    /// `MAPPING_*` bits
+    pub flags: u64,
+}
+
+impl core::fmt::Display for Mapping {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        let flag = |bit, c| if self.flags & bit != 0 { c } else { '-' };
+        write!(
+            f,
+            "{:#018x}-{:#018x} {}{}{}{}{}{} {:>6}K",
+            self.start,
+            self.end,
+            flag(MAPPING_READ, 'r'),
+            flag(MAPPING_WRITE, 'w'),
+            flag(MAPPING_EXEC, 'x'),
+            flag(MAPPING_USER, 'u'),
+            flag(MAPPING_GLOBAL, 'g'),
+            flag(MAPPING_COW, 'c'),
+            (self.end - self.start) / 1024,
+        )
+    }
+}
+
+/// System-wide memory usage, as reported by the `MEMINFO` syscall. Sizes are in bytes
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct MemInfo {
+    /// Physical memory handed out by the kernel, to itself and to processes
+    pub total: u64,
+    pub free: u64,
+    /// Size of the kernel's own heap, which is carved out of physical memory at boot
+    pub heap_total: u64,
+    pub heap_used: u64,
+    /// Memory taken up by page tables
+    pub page_tables: u64,
+    /// Size of the swap disk, 0 if there's none
+    pub swap_total: u64,
+    pub swap_used: u64,
+}
+
+/// Memory usage of a single process, as reported by the `MEMINFO` syscall. Sizes are in bytes
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ProcMemInfo {
+    pub pid: u64,
+    /// Size of every area the process may access, whether it's been touched or not
+    pub virtual_size: u64,
+    /// Memory mapped into the process, including memory it shares with others
+    pub resident: u64,
+    /// Memory of the process that's swapped out
+    pub swapped: u64,
 }
 
 impl Into<u64> for Syscall {
@@ -13,6 +109,17 @@ impl Into<u64> for Syscall {
             Self::PUTCHAR => 1,
             Self::GETCHAR => 2,
             Self::EXIT => 3,
+            Self::SBRK => 4,
+            Self::MMAP => 5,
+            Self::MUNMAP => 6,
+            Self::MPROTECT => 7,
+            Self::SHMOPEN => 8,
+            Self::SHMMAP => 9,
+            Self::SHMUNMAP => 10,
+            Self::SHMDESTROY => 11,
+            Self::FORK => 12,
+            Self::VMMAP => 13,
+            Self::MEMINFO => 14,
         }
     }
 }
@@ -24,6 +131,17 @@ impl TryFrom<u64> for Syscall {
             1 => Ok(Self::PUTCHAR),
             2 => Ok(Self::GETCHAR),
             3 => Ok(Self::EXIT),
+            4 => Ok(Self::SBRK),
+            5 => Ok(Self::MMAP),
+            6 => Ok(Self::MUNMAP),
+            7 => Ok(Self::MPROTECT),
+            8 => Ok(Self::SHMOPEN),
+            9 => Ok(Self::SHMMAP),
+            10 => Ok(Self::SHMUNMAP),
+            11 => Ok(Self::SHMDESTROY),
+            12 => Ok(Self::FORK),
+            13 => Ok(Self::VMMAP),
+            14 => Ok(Self::MEMINFO),
             _ => Err(value),
         }
     }
@@ -1,51 +1,235 @@
 use core::alloc::{GlobalAlloc, Layout};
+use core::ptr;
 
-use crate::println;
+use crate::memory::align_up;
 
-struct Mutable {
-    next: usize,
+/// Header of a free block, stored in the free memory itself
+struct FreeBlock {
+    size: usize,
+    next: Option<&'static mut FreeBlock>,
+}
+
+impl FreeBlock {
+    fn start(&self) -> usize {
+        self as *const Self as usize
+    }
+
+    fn end(&self) -> usize {
+        self.start() + self.size
+    }
+}
+
+/// Smallest block we can track: anything smaller can't hold a `FreeBlock` header
+const MIN_BLOCK_SIZE: usize = size_of::<FreeBlock>();
+const BLOCK_ALIGN: usize = align_of::<FreeBlock>();
+
+struct Heap {
+    /// Dummy head of the free list, which is kept sorted by address
+    head: FreeBlock,
+    start: usize,
     end: usize,
+    /// Bytes currently handed out, including padding
+    used: usize,
 }
 
-#[derive(Default)]
-pub struct BumpAllocator {
-    mutable: spin::Mutex<Option<Mutable>>,
+/// Snapshot of the state of the kernel heap
+#[derive(Debug, Clone, Copy, Default)]
+pub struct HeapStats {
+    pub total: usize,
+    pub used: usize,
+    pub free: usize,
+    pub free_blocks: usize,
+    pub largest_free: usize,
 }
 
-impl BumpAllocator {
+impl HeapStats {
+    /// External fragmentation, as a percentage.
+    /// 0 means all free memory is one contiguous block.
+    pub fn fragmentation(&self) -> usize {
+        (self.largest_free * 100)
+            .checked_div(self.free)
+            .map_or(0, |percent| 100 - percent)
+    }
+}
+
+impl core::fmt::Display for HeapStats {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "heap: {} / {} bytes used, {} free in {} blocks (largest {}), {}% fragmented",
+            self.used,
+            self.total,
+            self.free,
+            self.free_blocks,
+            self.largest_free,
+            self.fragmentation()
+        )
+    }
+}
+
+/// Round a layout up so that the block can later be returned to the free list
+fn block_size(layout: Layout) -> usize {
+    align_up(layout.size().max(MIN_BLOCK_SIZE), BLOCK_ALIGN)
+}
+
+impl Heap {
+    /// Insert a free region into the address-sorted free list,
+    /// merging it with its neighbours where they touch
+    unsafe fn insert(&mut self, addr: usize, size: usize) {
+        debug_assert!(size >= MIN_BLOCK_SIZE && addr % BLOCK_ALIGN == 0);
+        // Find the last block that starts before `addr`
+        let mut prev = &mut self.head;
+        while prev.next.as_ref().is_some_and(|next| next.start() < addr) {
+            prev = prev.next.as_mut().unwrap();
+        }
+
+        unsafe {
+            let block = addr as *mut FreeBlock;
+            block.write(FreeBlock {
+                size,
+                next: prev.next.take(),
+            });
+            let block = &mut *block;
+
+            // Merge with the following block
+            if let Some(next) = block.next.take() {
+                if block.end() == next.start() {
+                    block.size += next.size;
+                    block.next = next.next.take();
+                } else {
+                    block.next = Some(next);
+                }
+            }
+
+            // Merge with the preceding block (the dummy head has size 0 and never touches)
+            if prev.size != 0 && prev.end() == block.start() {
+                prev.size += block.size;
+                prev.next = block.next.take();
+            } else {
+                prev.next = Some(block);
+            }
+        }
+    }
+
+    /// First-fit search for a block that can hold `size` bytes aligned to `align`.
+    /// Returns the address of the allocation.
+    fn take(&mut self, size: usize, align: usize) -> Option<usize> {
+        let mut prev = &mut self.head;
+        loop {
+            let block = prev.next.as_mut()?;
+            let start = block.start();
+            let end = block.end();
+            let alloc_start = align_up(start, align);
+            let mut front = alloc_start - start;
+            // A gap in front of the allocation must be able to hold a header
+            let alloc_start = if front != 0 && front < MIN_BLOCK_SIZE {
+                let alloc_start = align_up(start + MIN_BLOCK_SIZE, align);
+                front = alloc_start - start;
+                alloc_start
+            } else {
+                alloc_start
+            };
+            let alloc_end = alloc_start.saturating_add(size);
+            let back = end.saturating_sub(alloc_end);
+            // So must a gap behind it. A block that would leave a smaller one is skipped, as
+            // `dealloc` only frees the allocation's own size and the gap would be lost for good
+            if alloc_end <= end && (back == 0 || back >= MIN_BLOCK_SIZE) {
+                let next = block.next.take();
+                prev.next = next;
+                unsafe {
+                    if front != 0 {
+                        self.insert(start, front);
+                    }
+                    if back != 0 {
+                        self.insert(alloc_end, back);
+                    }
+                }
+                return Some(alloc_start);
+            }
+            prev = prev.next.as_mut().unwrap();
+        }
+    }
+
+    fn stats(&self) -> HeapStats {
+        let mut stats = HeapStats {
+            total: self.end - self.start,
+            used: self.used,
+            ..HeapStats::default()
+        };
+        let mut current = self.head.next.as_ref();
+        while let Some(block) = current {
+            stats.free += block.size;
+            stats.free_blocks += 1;
+            stats.largest_free = stats.largest_free.max(block.size);
+            current = block.next.as_ref();
+        }
+        stats
+    }
+}
+
+/// First-fit free-list allocator.
+/// Free blocks are kept sorted by address so that neighbours can be coalesced on free.
+pub struct LinkedListAllocator {
+    heap: spin::Mutex<Option<Heap>>,
+}
+
+impl LinkedListAllocator {
     const fn new() -> Self {
         Self {
-            mutable: spin::Mutex::new(None),
+            heap: spin::Mutex::new(None),
         }
     }
 
     pub fn init(&self, start: *mut u8, end: *mut u8) {
-        self.mutable.lock().replace(Mutable {
-            next: start as usize,
-            end: end as usize,
-        });
+        let start = align_up(start as usize, BLOCK_ALIGN);
+        let end = end as usize & !(BLOCK_ALIGN - 1);
+        let mut heap = Heap {
+            head: FreeBlock {
+                size: 0,
+                next: None,
+            },
+            start,
+            end,
+            used: 0,
+        };
+        unsafe { heap.insert(start, end - start) };
+        self.heap.lock().replace(heap);
+    }
+
+    /// Get a snapshot of the current heap usage
+    pub fn stats(&self) -> HeapStats {
+        self.heap
+            .lock()
+            .as_ref()
+            .map(Heap::stats)
+            .unwrap_or_default()
     }
 }
 
-unsafe impl GlobalAlloc for BumpAllocator {
+unsafe impl GlobalAlloc for LinkedListAllocator {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        let mut lock = self.mutable.lock();
-        let mutable = lock.as_mut().expect("Allocator not initialized");
-
-        let addr = mutable.next.next_multiple_of(layout.align());
-        assert!(
-            addr.saturating_add(layout.size()) <= mutable.end,
-            "Out of Memory!"
-        );
+        let mut lock = self.heap.lock();
+        let heap = lock.as_mut().expect("Allocator not initialized");
 
-        mutable.next = addr + layout.size();
-        addr as *mut u8
+        let size = block_size(layout);
+        match heap.take(size, layout.align().max(BLOCK_ALIGN)) {
+            Some(addr) => {
+                heap.used += size;
+                addr as *mut u8
+            }
+            None => ptr::null_mut(),
+        }
     }
 
-    unsafe fn dealloc(&self, _ptr: *mut u8, _layout: Layout) {
-        println!("Dealloc called lol");
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let mut lock = self.heap.lock();
+        let heap = lock.as_mut().expect("Allocator not initialized");
+
+        let size = block_size(layout);
+        heap.used -= size;
+        unsafe { heap.insert(ptr as usize, size) };
     }
 }
 
 #[global_allocator]
-pub static GLOBAL_ALLOCATOR: BumpAllocator = BumpAllocator::new();
+pub static GLOBAL_ALLOCATOR: LinkedListAllocator = LinkedListAllocator::new();
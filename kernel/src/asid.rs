@@ -0,0 +1,80 @@
+use core::arch::asm;
+
+use crate::{println, read_csr, write_csr};
+
+/// Position of the ASID field in `satp`
+const SATP_ASID_SHIFT: u64 = 44;
+const SATP_ASID_MASK: u64 = 0xFFFF << SATP_ASID_SHIFT;
+
+/// An address space identifier, tagged with the generation it was handed out in.
+/// When the allocator runs out of ASIDs it starts a new generation and flushes the whole TLB,
+/// which invalidates every ASID from older generations.
+#[derive(Debug, Clone, Copy)]
+pub struct Asid {
+    value: u16,
+    generation: u64,
+}
+
+struct AsidAllocator {
+    /// Largest ASID the hart supports, 0 if it doesn't support ASIDs at all
+    max: u16,
+    next: u16,
+    generation: u64,
+}
+
+static ASIDS: spin::Mutex<AsidAllocator> = spin::Mutex::new(AsidAllocator {
+    max: 0,
+    next: 1,
+    generation: 0,
+});
+
+/// Find out how many ASID bits the hart implements.
+/// Unimplemented bits of the ASID field are hardwired to zero,
+/// so we write all ones and see which of them stick.
+pub fn init() {
+    let satp = read_csr!("satp");
+    write_csr!("satp", satp | SATP_ASID_MASK);
+    let probed = read_csr!("satp");
+    write_csr!("satp", satp);
+    let max = ((probed & SATP_ASID_MASK) >> SATP_ASID_SHIFT) as u16;
+    ASIDS.lock().max = max;
+    println!("ASID bits supported: {}", max.count_ones());
+}
+
+/// Is `asid` still valid, and if so, which value does it have
+pub fn current_value(asid: Option<Asid>) -> Option<u16> {
+    let asids = ASIDS.lock();
+    asid.filter(|asid| asid.generation == asids.generation && asids.max != 0)
+        .map(|asid| asid.value)
+}
+
+/// Make sure `asid` is valid in the current generation, allocating a new one if needed.
+/// Returns the ASID value to put in `satp`, and whether the TLB must be flushed after switching
+pub fn assign(asid: &mut Option<Asid>) -> (u16, bool) {
+    let mut asids = ASIDS.lock();
+    if asids.max == 0 {
+        // Without ASIDs, every address space shares ASID 0 and each switch must flush
+        return (0, true);
+    }
+    if let Some(current) = asid.filter(|asid| asid.generation == asids.generation) {
+        return (current.value, false);
+    }
+    if asids.next > asids.max || asids.next == 0 {
+        // Out of ASIDs: start a new generation, which makes every older ASID stale
+        asids.generation += 1;
+        asids.next = 1;
+        unsafe { asm!("sfence.vma") };
+    }
+    let value = asids.next;
+    asids.next = asids.next.wrapping_add(1);
+    *asid = Some(Asid {
+        value,
+        generation: asids.generation,
+    });
+    (value, false)
+}
+
+/// Build the `satp` value for a root table at `root_ppn` tagged with `asid`
+pub fn satp(mode: u64, asid: u16, root_ppn: usize) -> usize {
+    ((mode << 60) | ((asid as u64) << SATP_ASID_SHIFT) | root_ppn as u64) as usize
+}
@@ -1,11 +1,35 @@
 unsafe extern "C" {
     pub static mut __kernel_start: u8;
+    pub static mut __text_end: u8;
+    pub static mut __rodata_end: u8;
     pub static mut __bss: u8;
     pub static mut __bss_end: u8;
     pub static mut __heap: u8;
-    pub static mut __heap_end: u8;
 }
 
-pub const USER_BASE: usize = 0x1000000;
+/// User space lives entirely below this address, everything above belongs to the kernel
+pub const USER_END: u64 = 0x8000_0000;
+/// The user heap can grow up to here, stopping short of the MMIO devices mapped at 0x1000_0000
+pub const USER_HEAP_END: u64 = 0x1000_0000;
+/// `mmap` places mappings in this range
+pub const USER_MMAP_BASE: u64 = 0x2000_0000;
+pub const USER_MMAP_END: u64 = 0x6000_0000;
+/// User stacks grow down from here, with an unmapped guard page below them
+pub const USER_STACK_TOP: u64 = 0x7000_0000;
+/// Address space layout randomisation moves each process's position-independent image up from
+/// where it was linked by less than this, starts its heap less than this past the end of the image,
+/// and moves its stack top down from `USER_STACK_TOP` by less than this. Off with `norandmaps`.
+pub const USER_IMAGE_RANDOM: u64 = 0x400_0000;
+pub const USER_HEAP_RANDOM: u64 = 0x200_0000;
+pub const USER_STACK_RANDOM: u64 = 0x100_0000;
+/// Stack size for processes started by the kernel
+pub const USER_STACK_SIZE: usize = 64 * 1024;
 
-pub const SHELL: &[u8] = include_bytes!("../../shell.bin");
+/// Size of the kernel heap, carved out of the frame allocator at boot
+pub const KERNEL_HEAP_SIZE: usize = 8 * 1024 * 1024;
+/// Kernel stacks are mapped from here on, one per process slot with a guard page below each
+pub const KERNEL_STACKS_BASE: u64 = 0xFFFF_FFC0_0000_0000;
+/// Size of the kernel stack of each process
+pub const KERNEL_STACK_SIZE: usize = 16 * 1024;
+
+pub const SHELL: &[u8] = include_bytes!("../../shell.elf");
@@ -0,0 +1,283 @@
+const ELF_MAGIC: &[u8; 4] = b"\x7fELF";
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const ET_EXEC: u16 = 2;
+/// Position-independent executables are shared objects as far as the header is concerned
+const ET_DYN: u16 = 3;
+const EM_RISCV: u16 = 243;
+const PT_LOAD: u32 = 1;
+const PT_DYNAMIC: u32 = 2;
+/// Size of the part of a program header we read, the 64 bit one
+const PHDR_SIZE: usize = 56;
+
+/// Dynamic section tags
+const DT_NULL: u64 = 0;
+const DT_RELA: u64 = 7;
+const DT_RELASZ: u64 = 8;
+const DT_RELAENT: u64 = 9;
+const DT_REL: u64 = 17;
+const DT_RELR: u64 = 36;
+/// Size of a dynamic section entry, and of a relocation with an addend
+const DYN_SIZE: usize = 16;
+const RELA_SIZE: usize = 24;
+
+/// Relocation types
+const R_RISCV_NONE: u32 = 0;
+const R_RISCV_RELATIVE: u32 = 3;
+
+/// Segment permission bits in `p_flags`
+const PF_X: u32 = 1 << 0;
+const PF_W: u32 = 1 << 1;
+const PF_R: u32 = 1 << 2;
+
+#[derive(Debug, Clone)]
+pub enum ElfError {
+    BadMagic,
+    /// Not a statically linked, 64 bit, little-endian RISC-V executable
+    Unsupported,
+    Truncated,
+    /// A position-independent executable needs relocations other than relative ones,
+    /// which only a dynamic linker could resolve
+    UnsupportedRelocation(u32),
+    /// A relocation points outside the loaded image, or isn't aligned
+    BadRelocation(u64),
+    /// The segment linked at this address can't be loaded: it allows no access at all,
+    /// is writable and executable, overlaps another one or lies outside of user space
+    BadSegment(u64),
+}
+
+impl core::fmt::Display for ElfError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            ElfError::BadMagic => write!(f, "Not an ELF file"),
+            ElfError::Unsupported => write!(f, "Not a RISC-V 64 bit executable"),
+            ElfError::Truncated => write!(f, "ELF file is truncated"),
+            ElfError::UnsupportedRelocation(kind) => {
+                write!(f, "Unsupported relocation type {kind}")
+            }
+            ElfError::BadRelocation(vaddr) => write!(f, "Bad relocation at {vaddr:#x}"),
+            ElfError::BadSegment(vaddr) => write!(f, "Bad segment at {vaddr:#x}"),
+        }
+    }
+}
+
+impl core::error::Error for ElfError {}
+
+fn le16(data: &[u8], offset: usize) -> Result<u16, ElfError> {
+    data.get(offset..offset + 2)
+        .map(|bytes| u16::from_le_bytes(bytes.try_into().unwrap()))
+        .ok_or(ElfError::Truncated)
+}
+
+fn le32(data: &[u8], offset: usize) -> Result<u32, ElfError> {
+    data.get(offset..offset + 4)
+        .map(|bytes| u32::from_le_bytes(bytes.try_into().unwrap()))
+        .ok_or(ElfError::Truncated)
+}
+
+fn le64(data: &[u8], offset: usize) -> Result<u64, ElfError> {
+    data.get(offset..offset + 8)
+        .map(|bytes| u64::from_le_bytes(bytes.try_into().unwrap()))
+        .ok_or(ElfError::Truncated)
+}
+
+/// An ELF executable, read in place
+#[derive(Clone, Copy)]
+pub struct Elf<'a> {
+    data: &'a [u8],
+    entry: u64,
+    phoff: usize,
+    phentsize: usize,
+    phnum: usize,
+    /// Position-independent, so it can be loaded anywhere as long as it's relocated
+    pie: bool,
+    /// The relocation table of a position-independent executable, empty otherwise
+    relocations: &'a [u8],
+}
+
+impl<'a> Elf<'a> {
+    /// Check the header of the executable in `data`
+    pub fn parse(data: &'a [u8]) -> Result<Self, ElfError> {
+        if data.get(..4) != Some(ELF_MAGIC) {
+            return Err(ElfError::BadMagic);
+        }
+        let class = *data.get(4).ok_or(ElfError::Truncated)?;
+        let encoding = *data.get(5).ok_or(ElfError::Truncated)?;
+        let kind = le16(data, 16)?;
+        if class != ELFCLASS64
+            || encoding != ELFDATA2LSB
+            || (kind != ET_EXEC && kind != ET_DYN)
+            || le16(data, 18)? != EM_RISCV
+        {
+            return Err(ElfError::Unsupported);
+        }
+        let mut elf = Self {
+            data,
+            entry: le64(data, 24)?,
+            phoff: le64(data, 32)? as usize,
+            phentsize: le16(data, 54)? as usize,
+            phnum: le16(data, 56)? as usize,
+            pie: kind == ET_DYN,
+            relocations: &[],
+        };
+        // Make sure every segment can be read, so iterating over them can't fail
+        for index in 0..elf.phnum {
+            elf.segment(index)?;
+        }
+        if elf.pie {
+            elf.relocations = elf.find_relocations()?;
+            // Likewise every relocation has to be one we can apply
+            for rela in elf.relocations.chunks_exact(RELA_SIZE) {
+                let kind = le64(rela, 8)? as u32;
+                if kind != R_RISCV_NONE && kind != R_RISCV_RELATIVE {
+                    return Err(ElfError::UnsupportedRelocation(kind));
+                }
+            }
+        }
+        Ok(elf)
+    }
+
+    /// Address execution starts at
+    pub fn entry(&self) -> u64 {
+        self.entry
+    }
+
+    /// Can the executable be loaded at any address, rather than only where it was linked
+    pub fn is_pie(&self) -> bool {
+        self.pie
+    }
+
+    /// The relocations to apply after loading a position-independent executable `bias` bytes
+    /// above where it was linked. Each one is a place in memory and the value to store there.
+    pub fn relocations(
+        &self,
+        bias: u64,
+    ) -> impl Iterator<Item = Result<(u64, u64), ElfError>> + '_ {
+        self.relocations
+            .chunks_exact(RELA_SIZE)
+            // Every entry was checked by `parse`
+            .filter(|rela| le64(rela, 8).unwrap() as u32 == R_RISCV_RELATIVE)
+            .map(move |rela| {
+                let offset = le64(rela, 0).unwrap();
+                // The addend is signed, so adding to it wraps around like the sum it stands for
+                let addend = le64(rela, 16).unwrap();
+                let place = offset
+                    .checked_add(bias)
+                    .ok_or(ElfError::BadRelocation(offset))?;
+                Ok((place, addend.wrapping_add(bias)))
+            })
+    }
+
+    /// Find the relocation table through the dynamic section, if there is one
+    fn find_relocations(&self) -> Result<&'a [u8], ElfError> {
+        let mut dynamic = None;
+        for index in 0..self.phnum {
+            let header = self.program_header(index)?;
+            if le32(self.data, header)? == PT_DYNAMIC {
+                let offset = le64(self.data, header + 8)? as usize;
+                let size = le64(self.data, header + 32)? as usize;
+                let end = offset.checked_add(size).ok_or(ElfError::Truncated)?;
+                dynamic = Some(self.data.get(offset..end).ok_or(ElfError::Truncated)?);
+            }
+        }
+        let Some(dynamic) = dynamic else {
+            return Ok(&[]);
+        };
+        let (mut rela, mut size) = (None, 0);
+        for entry in dynamic.chunks_exact(DYN_SIZE) {
+            match le64(entry, 0)? {
+                DT_NULL => break,
+                DT_RELA => rela = Some(le64(entry, 8)?),
+                DT_RELASZ => size = le64(entry, 8)? as usize,
+                DT_RELAENT if le64(entry, 8)? != RELA_SIZE as u64 => {
+                    return Err(ElfError::Unsupported);
+                }
+                DT_REL | DT_RELR => return Err(ElfError::Unsupported),
+                _ => {}
+            }
+        }
+        // A table that doesn't hold a whole number of entries isn't one
+        if size % RELA_SIZE != 0 {
+            return Err(ElfError::Unsupported);
+        }
+        match rela {
+            Some(vaddr) => self.file_data(vaddr, size),
+            None => Ok(&[]),
+        }
+    }
+
+    /// The contents of the file that get loaded at `vaddr`, for `len` bytes
+    fn file_data(&self, vaddr: u64, len: usize) -> Result<&'a [u8], ElfError> {
+        let end = vaddr.checked_add(len as u64).ok_or(ElfError::Truncated)?;
+        self.segments()
+            .find(|segment| {
+                segment.vaddr <= vaddr
+                    && segment
+                        .vaddr
+                        .checked_add(segment.data.len() as u64)
+                        .is_some_and(|segment_end| end <= segment_end)
+            })
+            .map(|segment| &segment.data[(vaddr - segment.vaddr) as usize..][..len])
+            .ok_or(ElfError::Truncated)
+    }
+
+    /// The segments that get loaded into memory
+    pub fn segments(&self) -> impl Iterator<Item = Segment<'a>> + '_ {
+        (0..self.phnum).filter_map(|index| self.segment(index).ok().flatten())
+    }
+
+    /// Offset of program header `index` into the file, which holds all of it
+    fn program_header(&self, index: usize) -> Result<usize, ElfError> {
+        index
+            .checked_mul(self.phentsize)
+            .and_then(|offset| offset.checked_add(self.phoff))
+            .filter(|header| {
+                header
+                    .checked_add(PHDR_SIZE)
+                    .is_some_and(|end| end <= self.data.len())
+            })
+            .ok_or(ElfError::Truncated)
+    }
+
+    /// Read program header `index`, None if it isn't a loadable segment
+    fn segment(&self, index: usize) -> Result<Option<Segment<'a>>, ElfError> {
+        let header = self.program_header(index)?;
+        if le32(self.data, header)? != PT_LOAD {
+            return Ok(None);
+        }
+        let offset = le64(self.data, header + 8)? as usize;
+        let file_size = le64(self.data, header + 32)? as usize;
+        let end = offset.checked_add(file_size).ok_or(ElfError::Truncated)?;
+        let data = self.data.get(offset..end).ok_or(ElfError::Truncated)?;
+        Ok(Some(Segment {
+            vaddr: le64(self.data, header + 16)?,
+            mem_size: le64(self.data, header + 40)?,
+            flags: le32(self.data, header + 4)?,
+            data,
+        }))
+    }
+}
+
+/// A loadable segment of an executable.
+/// Memory past the end of `data` up to `mem_size` is zero-filled, which is where `.bss` lives.
+#[derive(Debug, Clone, Copy)]
+pub struct Segment<'a> {
+    pub vaddr: u64,
+    pub mem_size: u64,
+    pub data: &'a [u8],
+    flags: u32,
+}
+
+impl Segment<'_> {
+    pub fn readable(&self) -> bool {
+        self.flags & PF_R != 0
+    }
+
+    pub fn writable(&self) -> bool {
+        self.flags & PF_W != 0
+    }
+
+    pub fn executable(&self) -> bool {
+        self.flags & PF_X != 0
+    }
+}
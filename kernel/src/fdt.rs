@@ -0,0 +1,405 @@
+use alloc::string::String;
+
+const FDT_MAGIC: u32 = 0xd00dfeed;
+const FDT_BEGIN_NODE: u32 = 1;
+const FDT_END_NODE: u32 = 2;
+const FDT_PROP: u32 = 3;
+const FDT_NOP: u32 = 4;
+const FDT_END: u32 = 9;
+
+/// Deepest node nesting we keep track of cell sizes for
+const MAX_DEPTH: usize = 16;
+/// `#address-cells` and `#size-cells` when a node doesn't specify them
+const DEFAULT_CELLS: Cells = Cells {
+    address: 2,
+    size: 1,
+};
+
+#[derive(Debug, Clone)]
+pub enum FdtError {
+    BadMagic(u32),
+    Truncated,
+}
+
+impl core::fmt::Display for FdtError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            FdtError::BadMagic(magic) => write!(f, "Bad device tree magic: {magic:#x}"),
+            FdtError::Truncated => write!(f, "Device tree is truncated"),
+        }
+    }
+}
+
+impl core::error::Error for FdtError {}
+
+fn be32(data: &[u8], offset: usize) -> Result<u32, FdtError> {
+    data.get(offset..offset + 4)
+        .map(|bytes| u32::from_be_bytes(bytes.try_into().unwrap()))
+        .ok_or(FdtError::Truncated)
+}
+
+/// Read a big-endian number made of `cells` 32 bit cells
+fn read_cells(data: &[u8], cells: u32) -> Option<u64> {
+    let bytes = data.get(..cells as usize * 4)?;
+    Some(bytes.chunks_exact(4).fold(0, |acc, cell| {
+        (acc << 32) | u32::from_be_bytes(cell.try_into().unwrap()) as u64
+    }))
+}
+
+/// Read a NUL-terminated string starting at `offset`
+fn c_str(data: &[u8], offset: usize) -> Option<&str> {
+    let bytes = data.get(offset..)?;
+    let len = bytes.iter().position(|b| *b == 0)?;
+    core::str::from_utf8(&bytes[..len]).ok()
+}
+
+/// A region of physical memory
+#[derive(Debug, Clone, Copy)]
+pub struct Region {
+    pub start: u64,
+    pub size: u64,
+}
+
+impl Region {
+    pub fn end(&self) -> u64 {
+        self.start + self.size
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Cells {
+    address: u32,
+    size: u32,
+}
+
+/// A parsed flattened device tree, as passed to us by the firmware.
+/// Parsing is done lazily, nothing is copied out of the blob.
+#[derive(Clone, Copy)]
+pub struct Fdt<'a> {
+    blob: &'a [u8],
+    structs: &'a [u8],
+    strings: &'a [u8],
+    mem_rsvmap: usize,
+}
+
+impl<'a> Fdt<'a> {
+    /// Parse the device tree blob at `ptr`
+    /// SAFETY: `ptr` must point to a device tree that stays valid for `'a`
+    pub unsafe fn from_ptr(ptr: *const u8) -> Result<Self, FdtError> {
+        let header = unsafe { core::slice::from_raw_parts(ptr, 8) };
+        let magic = be32(header, 0)?;
+        if magic != FDT_MAGIC {
+            return Err(FdtError::BadMagic(magic));
+        }
+        let total_size = be32(header, 4)? as usize;
+        Self::from_bytes(unsafe { core::slice::from_raw_parts(ptr, total_size) })
+    }
+
+    pub fn from_bytes(blob: &'a [u8]) -> Result<Self, FdtError> {
+        let magic = be32(blob, 0)?;
+        if magic != FDT_MAGIC {
+            return Err(FdtError::BadMagic(magic));
+        }
+        let off_structs = be32(blob, 8)? as usize;
+        let off_strings = be32(blob, 12)? as usize;
+        let mem_rsvmap = be32(blob, 16)? as usize;
+        let size_strings = be32(blob, 32)? as usize;
+        let size_structs = be32(blob, 36)? as usize;
+        let structs = blob
+            .get(off_structs..off_structs + size_structs)
+            .ok_or(FdtError::Truncated)?;
+        let strings = blob
+            .get(off_strings..off_strings + size_strings)
+            .ok_or(FdtError::Truncated)?;
+        Ok(Self {
+            blob,
+            structs,
+            strings,
+            mem_rsvmap,
+        })
+    }
+
+    /// Size of the whole blob in bytes
+    pub fn total_size(&self) -> usize {
+        self.blob.len()
+    }
+
+    /// Iterate over every node in the tree, depth first
+    pub fn nodes(&self) -> NodeIter<'a> {
+        NodeIter {
+            fdt: *self,
+            offset: 0,
+            depth: 0,
+            cells: [DEFAULT_CELLS; MAX_DEPTH],
+        }
+    }
+
+    /// Find a node by its full path, e.g. `/chosen`.
+    /// Unit addresses may be left out, so `/cpus/cpu` matches `/cpus/cpu@0`.
+    pub fn find_node(&self, path: &str) -> Option<Node<'a>> {
+        let mut components = path.split('/').filter(|c| !c.is_empty());
+        let mut wanted = components.next();
+        // Depth of the deepest node on the path we've matched so far
+        let mut matched = 0;
+        for node in self.nodes() {
+            if node.depth <= matched && node.depth != 0 {
+                // We've left the subtree we were searching
+                return None;
+            }
+            if node.depth == 0 {
+                if wanted.is_none() {
+                    return Some(node);
+                }
+                continue;
+            }
+            if node.depth == matched + 1 && wanted.is_some_and(|w| node.matches(w)) {
+                matched += 1;
+                wanted = components.next();
+                if wanted.is_none() {
+                    return Some(node);
+                }
+            }
+        }
+        None
+    }
+
+    /// Iterate over every node whose `compatible` property lists `compatible`
+    pub fn compatible_nodes<'s>(
+        &self,
+        compatible: &'s str,
+    ) -> impl Iterator<Item = Node<'a>> + use<'a, 's> {
+        self.nodes()
+            .filter(move |node| node.compatible().any(|c| c == compatible))
+    }
+
+    /// Physical memory, as described by the `/memory` nodes
+    pub fn memory_regions(&self) -> impl Iterator<Item = Region> + use<'a> {
+        self.nodes()
+            .filter(|node| node.depth == 1 && node.property_str("device_type") == Some("memory"))
+            .flat_map(|node| node.reg())
+    }
+
+    /// Memory the firmware asked us not to touch,
+    /// from both the memory reservation block and `/reserved-memory`
+    pub fn reserved_regions(&self) -> impl Iterator<Item = Region> + use<'a> {
+        let blob = self.blob;
+        let rsvmap = (self.mem_rsvmap..)
+            .step_by(16)
+            .map(move |offset| {
+                let entry = blob.get(offset..offset + 16)?;
+                Some(Region {
+                    start: read_cells(&entry[..8], 2)?,
+                    size: read_cells(&entry[8..], 2)?,
+                })
+            })
+            .take_while(|entry| entry.is_some_and(|region| region.size != 0))
+            .flatten();
+        let reserved_memory = self.find_node("/reserved-memory");
+        let nodes = self.nodes();
+        let reserved_nodes = reserved_memory
+            .into_iter()
+            .flat_map(move |parent| {
+                nodes
+                    .clone()
+                    .skip_while(move |node| node.offset != parent.offset)
+                    .skip(1)
+                    .take_while(move |node| node.depth > parent.depth)
+                    .filter(move |node| node.depth == parent.depth + 1)
+            })
+            .flat_map(|node| node.reg());
+        rsvmap.chain(reserved_nodes)
+    }
+
+    /// Kernel command line from `/chosen`
+    pub fn bootargs(&self) -> Option<&'a str> {
+        self.find_node("/chosen")?.property_str("bootargs")
+    }
+
+    /// Frequency of the `time` CSR, in Hz
+    pub fn timebase_frequency(&self) -> Option<u64> {
+        let cpus = self.find_node("/cpus")?;
+        let value = cpus
+            .property("timebase-frequency")
+            .or_else(|| self.find_node("/cpus/cpu")?.property("timebase-frequency"))?;
+        read_cells(value, value.len() as u32 / 4)
+    }
+}
+
+/// Depth-first iterator over the nodes of a device tree
+#[derive(Clone)]
+pub struct NodeIter<'a> {
+    fdt: Fdt<'a>,
+    /// Offset of the next token in the structure block
+    offset: usize,
+    /// Depth of the next node we'll see
+    depth: usize,
+    /// The `#address-cells` and `#size-cells` declared by each of the current node's ancestors
+    cells: [Cells; MAX_DEPTH],
+}
+
+impl<'a> Iterator for NodeIter<'a> {
+    type Item = Node<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let structs = self.fdt.structs;
+        loop {
+            let token = be32(structs, self.offset).ok()?;
+            match token {
+                FDT_BEGIN_NODE => {
+                    let name = c_str(structs, self.offset + 4)?;
+                    let props = self.offset + 4 + align4(name.len() + 1);
+                    let node = Node {
+                        fdt: self.fdt,
+                        name,
+                        depth: self.depth,
+                        offset: self.offset,
+                        props,
+                        parent_cells: if self.depth == 0 {
+                            DEFAULT_CELLS
+                        } else {
+                            self.cells[(self.depth - 1).min(MAX_DEPTH - 1)]
+                        },
+                    };
+                    // Record the cell sizes this node declares for its children
+                    let cells = Cells {
+                        address: node
+                            .property_u32("#address-cells")
+                            .unwrap_or(DEFAULT_CELLS.address),
+                        size: node
+                            .property_u32("#size-cells")
+                            .unwrap_or(DEFAULT_CELLS.size),
+                    };
+                    self.cells[self.depth.min(MAX_DEPTH - 1)] = cells;
+                    self.depth += 1;
+                    self.offset = props;
+                    return Some(node);
+                }
+                FDT_END_NODE => {
+                    self.depth = self.depth.checked_sub(1)?;
+                    self.offset += 4;
+                }
+                FDT_PROP => {
+                    let len = be32(structs, self.offset + 4).ok()? as usize;
+                    self.offset += 12 + align4(len);
+                }
+                FDT_NOP => self.offset += 4,
+                FDT_END => return None,
+                // Anything else means the blob is malformed, so stop here
+                _ => return None,
+            }
+        }
+    }
+}
+
+fn align4(value: usize) -> usize {
+    value.next_multiple_of(4)
+}
+
+/// A node of the device tree
+#[derive(Clone, Copy)]
+pub struct Node<'a> {
+    fdt: Fdt<'a>,
+    name: &'a str,
+    depth: usize,
+    /// Offset of the node's BEGIN_NODE token
+    offset: usize,
+    /// Offset of the node's first property
+    props: usize,
+    /// Cell sizes used to interpret this node's `reg` property
+    parent_cells: Cells,
+}
+
+impl<'a> Node<'a> {
+    /// Full name of the node, including the unit address
+    pub fn name(&self) -> &'a str {
+        self.name
+    }
+
+    /// Does this node have the name `name`, with or without the unit address
+    fn matches(&self, name: &str) -> bool {
+        self.name == name || self.name.split('@').next() == Some(name)
+    }
+
+    /// Iterate over the node's properties as `(name, value)` pairs
+    pub fn properties(&self) -> impl Iterator<Item = (&'a str, &'a [u8])> + use<'a> {
+        let fdt = self.fdt;
+        let mut offset = self.props;
+        core::iter::from_fn(move || {
+            loop {
+                match be32(fdt.structs, offset).ok()? {
+                    FDT_PROP => {
+                        let len = be32(fdt.structs, offset + 4).ok()? as usize;
+                        let name_offset = be32(fdt.structs, offset + 8).ok()? as usize;
+                        let value = fdt.structs.get(offset + 12..offset + 12 + len)?;
+                        offset += 12 + align4(len);
+                        return Some((c_str(fdt.strings, name_offset)?, value));
+                    }
+                    FDT_NOP => offset += 4,
+                    _ => return None,
+                }
+            }
+        })
+    }
+
+    pub fn property(&self, name: &str) -> Option<&'a [u8]> {
+        self.properties()
+            .find(|(prop, _)| *prop == name)
+            .map(|(_, value)| value)
+    }
+
+    pub fn property_u32(&self, name: &str) -> Option<u32> {
+        be32(self.property(name)?, 0).ok()
+    }
+
+    pub fn property_str(&self, name: &str) -> Option<&'a str> {
+        c_str(self.property(name)?, 0)
+    }
+
+    /// The strings in the `compatible` property
+    pub fn compatible(&self) -> impl Iterator<Item = &'a str> + use<'a> {
+        self.property("compatible")
+            .unwrap_or_default()
+            .split(|b| *b == 0)
+            .filter(|s| !s.is_empty())
+            .filter_map(|s| core::str::from_utf8(s).ok())
+    }
+
+    /// The address ranges in the `reg` property
+    pub fn reg(&self) -> impl Iterator<Item = Region> + use<'a> {
+        let cells = self.parent_cells;
+        let entry_size = (cells.address + cells.size) as usize * 4;
+        self.property("reg")
+            .unwrap_or_default()
+            .chunks_exact(entry_size.max(4))
+            .filter_map(move |entry| {
+                Some(Region {
+                    start: read_cells(entry, cells.address)?,
+                    size: read_cells(&entry[cells.address as usize * 4..], cells.size)?,
+                })
+            })
+    }
+}
+
+/// What we learned about the machine at boot
+#[derive(Debug)]
+pub struct BootInfo {
+    /// The RAM region the kernel was loaded into
+    pub ram: Region,
+    pub timebase_frequency: u64,
+    pub bootargs: String,
+}
+
+impl BootInfo {
+    /// Was the kernel booted with the flag `name` on its command line?
+    pub fn has_arg(&self, name: &str) -> bool {
+        self.bootargs.split_whitespace().any(|arg| arg == name)
+    }
+}
+
+pub static BOOT_INFO: spin::Once<BootInfo> = spin::Once::new();
+
+/// Get the boot information
+/// PANICS: if called before the device tree has been parsed
+pub fn boot_info() -> &'static BootInfo {
+    BOOT_INFO.get().expect("Boot info not initialized")
+}
@@ -0,0 +1,263 @@
+use crate::memory::{PAGE_SIZE, Paddr, align_up};
+use crate::swap;
+
+const BITS_PER_WORD: usize = u64::BITS as usize;
+
+struct Frames {
+    /// Physical address of the first managed frame
+    base: usize,
+    /// Number of managed frames
+    count: usize,
+    /// One bit per frame, set when the frame is in use
+    bitmap: &'static mut [u64],
+    /// References to each frame in use beyond the first, taken with `share`
+    extra_refs: &'static mut [u16],
+    /// Lowest frame index that might be free
+    hint: usize,
+    /// Number of frames currently free
+    free: usize,
+}
+
+impl Frames {
+    fn is_used(&self, index: usize) -> bool {
+        self.bitmap[index / BITS_PER_WORD] & (1 << (index % BITS_PER_WORD)) != 0
+    }
+
+    fn set_used(&mut self, index: usize, used: bool) {
+        let bit = 1 << (index % BITS_PER_WORD);
+        if used {
+            self.bitmap[index / BITS_PER_WORD] |= bit;
+        } else {
+            self.bitmap[index / BITS_PER_WORD] &= !bit;
+        }
+    }
+
+    /// Find the first run of `n` free frames at or after `from`,
+    /// starting at a physical address that is a multiple of `align` frames
+    fn find_run(&self, from: usize, n: usize, align: usize) -> Option<usize> {
+        let mut start = self.align_index(from, align);
+        while start + n <= self.count {
+            // Skip over fully allocated words when looking for the start of a run
+            if start % BITS_PER_WORD == 0 && self.bitmap[start / BITS_PER_WORD] == u64::MAX {
+                start = self.align_index(start + BITS_PER_WORD, align);
+                continue;
+            }
+            match (start..start + n).find(|i| self.is_used(*i)) {
+                Some(used) => start = self.align_index(used + 1, align),
+                None => return Some(start),
+            }
+        }
+        None
+    }
+
+    /// Round a frame index up so that its physical address is a multiple of `align` frames
+    fn align_index(&self, index: usize, align: usize) -> usize {
+        let frame_number = self.base / PAGE_SIZE + index;
+        frame_number.next_multiple_of(align) - self.base / PAGE_SIZE
+    }
+
+    fn index_of(&self, paddr: Paddr) -> usize {
+        let addr = paddr.0 as usize;
+        assert!(
+            addr >= self.base && addr < self.base + self.count * PAGE_SIZE,
+            "Frame {:?} is not managed by the frame allocator",
+            paddr
+        );
+        assert!(
+            addr % PAGE_SIZE == 0,
+            "Frame {:?} is not page-aligned",
+            paddr
+        );
+        (addr - self.base) / PAGE_SIZE
+    }
+}
+
+/// Allocator for physical page frames.
+/// Tracks every frame in the managed region with a bitmap and a reference count,
+/// which live in the first few frames of the region itself.
+pub struct FrameAllocator {
+    frames: spin::Mutex<Option<Frames>>,
+}
+
+impl FrameAllocator {
+    const fn new() -> Self {
+        Self {
+            frames: spin::Mutex::new(None),
+        }
+    }
+
+    /// Hand the physical memory between `start` and `end` to the allocator
+    pub fn init(&self, start: *mut u8, end: *mut u8) {
+        let start = align_up(start as usize, PAGE_SIZE);
+        let end = end as usize & !(PAGE_SIZE - 1);
+        let total = (end - start) / PAGE_SIZE;
+        // Carve the bitmap and reference counts out of the front of the region
+        let words = total.div_ceil(BITS_PER_WORD);
+        let refs_offset = words * size_of::<u64>();
+        let metadata_bytes = refs_offset + total * size_of::<u16>();
+        let metadata_frames = metadata_bytes.div_ceil(PAGE_SIZE);
+        let count = total - metadata_frames;
+        let (bitmap, extra_refs) = unsafe {
+            core::ptr::write_bytes(start as *mut u8, 0, metadata_bytes);
+            (
+                core::slice::from_raw_parts_mut(start as *mut u64, words),
+                core::slice::from_raw_parts_mut((start + refs_offset) as *mut u16, count),
+            )
+        };
+        self.frames.lock().replace(Frames {
+            base: start + metadata_frames * PAGE_SIZE,
+            count,
+            bitmap,
+            extra_refs,
+            hint: 0,
+            free: count,
+        });
+    }
+
+    /// Mark every managed frame overlapping `start..end` as permanently in use,
+    /// so memory that firmware handed to us can't be given out
+    pub fn reserve(&self, start: usize, end: usize) {
+        let mut lock = self.frames.lock();
+        let frames = lock.as_mut().expect("Frame allocator not initialized");
+        let limit = frames.base + frames.count * PAGE_SIZE;
+        let start = start.max(frames.base);
+        let end = end.min(limit);
+        if start >= end {
+            return;
+        }
+        let first = (start - frames.base) / PAGE_SIZE;
+        let last = (end - frames.base).div_ceil(PAGE_SIZE);
+        for index in first..last {
+            if !frames.is_used(index) {
+                frames.set_used(index, true);
+                frames.free -= 1;
+            }
+        }
+    }
+
+    /// Allocate `n` physically contiguous frames, starting at a multiple of `align` frames.
+    /// The contents of the frames are undefined.
+    pub fn alloc_aligned(&self, n: usize, align: usize) -> Option<Paddr> {
+        let mut lock = self.frames.lock();
+        let frames = lock.as_mut().expect("Frame allocator not initialized");
+        if n == 0 || n > frames.free {
+            return None;
+        }
+        let first = frames
+            .find_run(frames.hint, n, align)
+            .or_else(|| frames.find_run(0, n, align))?;
+        for index in first..first + n {
+            frames.set_used(index, true);
+        }
+        frames.free -= n;
+        if first == frames.hint {
+            frames.hint = first + n;
+        }
+        Some(Paddr((frames.base + first * PAGE_SIZE) as *mut u8))
+    }
+
+    /// Drop a reference to each of the `n` contiguous frames starting at `paddr`,
+    /// returning those nobody else holds a reference to to the allocator
+    pub fn free(&self, paddr: Paddr, n: usize) {
+        let mut lock = self.frames.lock();
+        let frames = lock.as_mut().expect("Frame allocator not initialized");
+        let first = frames.index_of(paddr);
+        for index in first..first + n {
+            assert!(
+                frames.is_used(index),
+                "Double free of frame {:#x}",
+                frames.base + index * PAGE_SIZE
+            );
+            if frames.extra_refs[index] > 0 {
+                frames.extra_refs[index] -= 1;
+            } else {
+                frames.set_used(index, false);
+                frames.free += 1;
+                frames.hint = frames.hint.min(index);
+            }
+        }
+    }
+
+    /// Take another reference to each of the `n` contiguous frames starting at `paddr`,
+    /// so they take one more `free` to be returned to the allocator
+    pub fn share(&self, paddr: Paddr, n: usize) {
+        let mut lock = self.frames.lock();
+        let frames = lock.as_mut().expect("Frame allocator not initialized");
+        let first = frames.index_of(paddr);
+        for index in first..first + n {
+            assert!(
+                frames.is_used(index),
+                "Sharing free frame {:#x}",
+                frames.base + index * PAGE_SIZE
+            );
+            frames.extra_refs[index] = frames.extra_refs[index]
+                .checked_add(1)
+                .expect("Too many references to frame");
+        }
+    }
+
+    /// Number of references to the frame at `paddr`, 0 if it's free
+    pub fn ref_count(&self, paddr: Paddr) -> usize {
+        let lock = self.frames.lock();
+        let frames = lock.as_ref().expect("Frame allocator not initialized");
+        let index = frames.index_of(paddr);
+        if frames.is_used(index) {
+            frames.extra_refs[index] as usize + 1
+        } else {
+            0
+        }
+    }
+
+    /// Number of frames that are currently free
+    pub fn free_count(&self) -> usize {
+        self.frames.lock().as_ref().map_or(0, |frames| frames.free)
+    }
+
+    /// Total number of frames managed by the allocator
+    pub fn total_count(&self) -> usize {
+        self.frames.lock().as_ref().map_or(0, |frames| frames.count)
+    }
+}
+
+pub static FRAME_ALLOCATOR: FrameAllocator = FrameAllocator::new();
+
+/// Allocate `n` contiguous, zeroed frames
+/// PANICS: if physical memory is exhausted
+pub fn alloc_frames(n: usize) -> Paddr {
+    alloc_frames_aligned(n, 1)
+}
+
+/// Allocate `n` contiguous, zeroed frames, starting at a multiple of `align` frames.
+/// PANICS: if physical memory is exhausted
+pub fn alloc_frames_aligned(n: usize, align: usize) -> Paddr {
+    try_alloc_frames_aligned(n, align).expect("Out of physical memory!")
+}
+
+/// Allocate `n` contiguous, zeroed frames, or None if physical memory is exhausted
+pub fn try_alloc_frames(n: usize) -> Option<Paddr> {
+    try_alloc_frames_aligned(n, 1)
+}
+
+/// Allocate `n` contiguous, zeroed frames, starting at a multiple of `align` frames.
+/// When memory runs out, user pages are swapped out to make room.
+/// Returns None once nothing more can be swapped out. Killing processes is left to callers that
+/// know they hold no locks, as this is called from all over the kernel, swapping included.
+pub fn try_alloc_frames_aligned(n: usize, align: usize) -> Option<Paddr> {
+    let paddr = loop {
+        match FRAME_ALLOCATOR.alloc_aligned(n, align) {
+            Some(paddr) => break paddr,
+            None if swap::reclaim() => continue,
+            None => return None,
+        }
+    };
+    unsafe {
+        core::ptr::write_bytes(paddr.0, 0, n * PAGE_SIZE);
+    }
+    Some(paddr)
+}
+
+/// Free `n` contiguous frames previously returned by `alloc_frames`,
+/// or drop a reference to them if they're shared
+pub fn free_frames(paddr: Paddr, n: usize) {
+    FRAME_ALLOCATOR.free(paddr, n);
+}
@@ -3,18 +3,29 @@
 
 extern crate alloc;
 mod allocator;
+mod asid;
 mod constants;
 mod dummy_procs;
+mod elf;
+mod fdt;
+mod frame;
 mod memory;
 mod process;
+mod random;
 mod sbi;
+mod shm;
+mod slab;
+mod swap;
 mod tar;
 mod virtio;
+mod vma;
 #[macro_use]
 mod print;
 #[macro_use]
 mod trap;
+mod uaccess;
 
+use alloc::boxed::Box;
 use alloc::slice;
 use alloc::string::String;
 use constants::*;
@@ -29,6 +40,7 @@ use crate::tar::BlockDevice;
 pub extern "C" fn boot() -> ! {
     unsafe {
         asm!(
+            // OpenSBI passes the hart id in a0 and the device tree in a1, which we pass along to main
             "la sp, __stack_top", // Load __stack_top address into sp
             "j {main}",           // Jump to main
             main = sym main,
@@ -47,7 +59,7 @@ pub fn panic_handler(info: &PanicInfo) -> ! {
     }
 }
 
-fn main() -> ! {
+extern "C" fn main(hart_id: usize, dtb: *const u8) -> ! {
     unsafe {
         let bss_start = &raw mut __bss;
         let bss_size = (&raw mut __bss_end as usize) - (&raw mut __bss as usize);
@@ -62,14 +74,74 @@ fn main() -> ! {
         println!("stvec set to: {:#x}", stvec_val);
     }
 
-    allocator::GLOBAL_ALLOCATOR.init(&raw mut __heap, &raw mut __heap_end);
-    println!("Allocator initialized!");
+    let fdt = unsafe { fdt::Fdt::from_ptr(dtb) }.expect("Invalid device tree");
+    println!(
+        "Booting on hart {hart_id}, device tree at {:#x}",
+        dtb as usize
+    );
 
-    let mut driver = virtio::BlockDeviceDriver::new();
-    let dev = BlockDevice::init(&mut driver).expect("Error initializing block device");
-    let fs = tar::FileSystem::init(&dev).expect("Error intializing filesystem");
+    // Free memory runs from the end of the kernel image to the end of the RAM region we were loaded into
+    let kernel_start = &raw mut __kernel_start as u64;
+    let ram = fdt
+        .memory_regions()
+        .find(|region| region.start <= kernel_start && kernel_start < region.end())
+        .expect("No memory region contains the kernel");
+    println!("RAM: {:#x} - {:#x}", ram.start, ram.end());
 
-    process::create_process(constants::SHELL);
+    frame::FRAME_ALLOCATOR.init(&raw mut __heap, ram.end() as *mut u8);
+    // Don't hand out the device tree itself, or anything the firmware reserved
+    frame::FRAME_ALLOCATOR.reserve(dtb as usize, dtb as usize + fdt.total_size());
+    for region in fdt.reserved_regions() {
+        frame::FRAME_ALLOCATOR.reserve(region.start as usize, region.end() as usize);
+    }
+    println!(
+        "Frame allocator initialized with {} free frames",
+        frame::FRAME_ALLOCATOR.free_count()
+    );
+
+    // The kernel heap is carved out of physical memory like everything else
+    let heap = frame::alloc_frames(KERNEL_HEAP_SIZE / memory::PAGE_SIZE);
+    allocator::GLOBAL_ALLOCATOR.init(heap.0, unsafe { heap.0.add(KERNEL_HEAP_SIZE) });
+    println!(
+        "Allocator initialized! {}",
+        allocator::GLOBAL_ALLOCATOR.stats()
+    );
+
+    // Now that we have a heap, hang on to what we need from the device tree
+    let boot_info = fdt::BOOT_INFO.call_once(|| fdt::BootInfo {
+        ram,
+        timebase_frequency: fdt.timebase_frequency().unwrap_or(0),
+        bootargs: fdt.bootargs().unwrap_or_default().into(),
+    });
+    println!(
+        "Timebase: {} Hz, bootargs: {:?}",
+        boot_info.timebase_frequency, boot_info.bootargs
+    );
+    for node in fdt.compatible_nodes("virtio,mmio") {
+        if let Some(reg) = node.reg().next() {
+            println!("virtio-mmio slot {} at {:#x}", node.name(), reg.start);
+        }
+    }
+
+    memory::init_kernel_space();
+    println!("Kernel address space initialized!");
+    asid::init();
+    random::init();
+    if boot_info.has_arg("norandmaps") {
+        println!("Address space layout randomisation disabled");
+    }
+
+    // The filesystem stays around for as long as the kernel runs, so processes can map its files
+    let driver = Box::leak(Box::new(virtio::BlockDeviceDriver::new(
+        virtio::VIRTIO_BLK_PADDR,
+    )));
+    let dev = BlockDevice::init(driver).expect("Error initializing block device");
+    tar::init_filesystem(Box::leak(Box::new(dev))).expect("Error intializing filesystem");
+    swap::init(virtio::VIRTIO_SWAP_PADDR);
+
+    if let Err(err) = process::create_process(constants::SHELL, constants::USER_STACK_SIZE) {
+        panic!("Couldn't start the shell: {err}");
+    }
 
     process::ps();
 
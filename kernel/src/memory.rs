@@ -1,6 +1,19 @@
-use core::alloc::{GlobalAlloc, Layout};
+use alloc::vec::Vec;
+use common::{
+    MAPPING_COW, MAPPING_EXEC, MAPPING_GLOBAL, MAPPING_READ, MAPPING_USER, MAPPING_WRITE, Mapping,
+    MemInfo, ProcMemInfo,
+};
+use core::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
 
-use crate::allocator;
+use crate::asid::{self, Asid};
+use crate::constants::{
+    self, KERNEL_STACK_SIZE, KERNEL_STACKS_BASE, USER_END, USER_HEAP_END, USER_MMAP_BASE,
+    USER_MMAP_END,
+};
+use crate::slab::SlabCache;
+use crate::virtio::{VIRTIO_BLK_PADDR, VIRTIO_SWAP_PADDR};
+use crate::vma::{Access, FaultError, Vma, VmaKind};
+use crate::{allocator, fdt, frame, println, read_csr, swap};
 
 pub const PAGE_SIZE: usize = 4096;
 
@@ -9,7 +22,7 @@ pub struct Paddr(pub *mut u8);
 
 impl core::fmt::Debug for Paddr {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
-        write!(f, "(Phys: 0x{:#x})", self.0 as u64)
+        write!(f, "(Phys: {:#x})", self.0 as u64)
     }
 }
 
@@ -20,6 +33,8 @@ impl core::fmt::Debug for Paddr {
 /// | (must |   (9 bits)    |   (9 bits)    |   (9 bits)    |   (12 bits)   |
 /// | be 0) |               |               |               |               |
 /// +-------+---------------+---------------+---------------+---------------+
+/// Sv48 adds another 9 bit index for level 3 in bits 39 to 47.
+/// Either way, the unused bits must be copies of the highest bit in use.
 
 #[derive(Copy, Clone)]
 #[repr(transparent)]
@@ -41,7 +56,7 @@ impl Vaddr {
 
 impl core::fmt::Debug for Vaddr {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
-        write!(f, "(Virt: 0x{:#x})", self.as_number())
+        write!(f, "(Virt: {:#x})", self.as_number())
     }
 }
 
@@ -126,28 +141,128 @@ impl PTE {
         (self.0 & (1 << 4)) != 0
     }
 
+    pub fn global(self) -> bool {
+        (self.0 & (1 << 5)) != 0
+    }
+
     pub fn with_flags(self, flags: PageFlags) -> Self {
         Self(self.0 | flags.as_raw())
     }
+
+    /// Replace the R/W/X/U bits with `flags`, keeping the frame and other bits
+    pub fn with_permissions(self, flags: PageFlags) -> Self {
+        Self(self.0 & !PERMISSION_BITS).with_flags(flags)
+    }
+
+    /// A valid entry with any of R/W/X set maps memory, otherwise it points to the next level table
+    pub fn is_leaf(self) -> bool {
+        self.read() || self.write() || self.x()
+    }
+
+    /// The physical address this entry points to
+    pub fn paddr(self) -> Paddr {
+        Paddr(((self.0 as usize >> 10) << 12) as *mut u8)
+    }
+
+    /// Is this page shared copy-on-write
+    pub fn cow(self) -> bool {
+        (self.0 & COW_BIT) != 0
+    }
+
+    /// Share the page copy-on-write, which makes it read-only until it's copied
+    pub fn with_cow(self) -> Self {
+        Self((self.0 | COW_BIT) & !(1 << 2))
+    }
+
+    /// Make a copy-on-write page private and writable again
+    pub fn without_cow(self) -> Self {
+        Self((self.0 & !COW_BIT) | (1 << 2))
+    }
+
+    /// Has the page been accessed since the bit was last cleared. Set by the hardware
+    pub fn accessed(self) -> bool {
+        (self.0 & ACCESSED_BIT) != 0
+    }
+
+    /// Has the page been written to since it was mapped. Set by the hardware
+    pub fn dirty(self) -> bool {
+        (self.0 & DIRTY_BIT) != 0
+    }
+
+    pub fn without_accessed(self) -> Self {
+        Self(self.0 & !ACCESSED_BIT)
+    }
+
+    /// An entry for a page that was swapped out to `slot`.
+    /// It isn't valid, so the hardware ignores it and accessing the page faults.
+    pub fn swapped(slot: usize) -> Self {
+        Self(((slot as u64) << 10) | SWAP_BIT)
+    }
+
+    /// The swap slot holding this page, if it was swapped out
+    pub fn swap_slot(self) -> Option<usize> {
+        (!self.valid() && (self.0 & SWAP_BIT) != 0).then_some((self.0 >> 10) as usize)
+    }
+
+    /// The `MAPPING_*` bits describing what this entry allows
+    pub fn mapping_flags(self) -> u64 {
+        let mut flags = 0;
+        flags |= if self.read() { MAPPING_READ } else { 0 };
+        flags |= if self.write() { MAPPING_WRITE } else { 0 };
+        flags |= if self.x() { MAPPING_EXEC } else { 0 };
+        flags |= if self.u() { MAPPING_USER } else { 0 };
+        flags |= if self.global() { MAPPING_GLOBAL } else { 0 };
+        flags |= if self.cow() { MAPPING_COW } else { 0 };
+        flags
+    }
 }
 
-#[derive(Default)]
+/// The R, W, X and U bits of a page table entry
+const PERMISSION_BITS: u64 = 0b1_1110;
+/// Every non-address bit of a page table entry
+const FLAG_BITS: u64 = 0x3FF;
+/// One of the RSW bits, which are ours to use: marks a page shared copy-on-write
+const COW_BIT: u64 = 1 << 8;
+/// The other RSW bit: marks an invalid entry as holding the swap slot of a swapped-out page
+const SWAP_BIT: u64 = 1 << 9;
+const ACCESSED_BIT: u64 = 1 << 6;
+const DIRTY_BIT: u64 = 1 << 7;
+
+/// A single page-sized level of the page table
+#[repr(C, align(4096))]
+pub struct PageTable([PTE; 512]);
+
+/// Cache of page-table pages, which are allocated and freed constantly as processes come and go
+pub static PAGE_TABLES: SlabCache<PageTable> = SlabCache::new("page_table");
+
+/// Allocate an empty page table
+pub fn alloc_page_table() -> *mut PTE {
+    unsafe { PAGE_TABLES.alloc_zeroed() as *mut PTE }
+}
+
+/// Allocate an empty page table, unless memory has run out
+pub fn try_alloc_page_table() -> Result<*mut PTE, OutOfMemory> {
+    unsafe { PAGE_TABLES.try_alloc_zeroed() }
+        .map(|table| table as *mut PTE)
+        .ok_or(OutOfMemory)
+}
+
+/// Return a page table to the cache
+/// SAFETY: `table` must have come from `alloc_page_table` and no longer be referenced
+pub unsafe fn free_page_table(table: *mut PTE) {
+    unsafe { PAGE_TABLES.free(table as *mut PageTable) };
+}
+
+#[derive(Debug, Default, Clone, Copy)]
 pub struct PageFlags {
     read: bool,
     write: bool,
     execute: bool,
     user: bool,
+    global: bool,
 }
 
 impl PageFlags {
-    pub fn kernel_all() -> Self {
-        Self::default().read().write().execute()
-    }
-
-    pub fn all() -> Self {
-        Self::default().read().write().execute().user()
-    }
-
     pub fn read(mut self) -> Self {
         self.read = true;
         self
@@ -164,6 +279,31 @@ impl PageFlags {
         self.user = true;
         self
     }
+    pub fn global(mut self) -> Self {
+        self.global = true;
+        self
+    }
+
+    pub fn can_read(self) -> bool {
+        self.read
+    }
+    pub fn can_write(self) -> bool {
+        self.write
+    }
+    pub fn can_execute(self) -> bool {
+        self.execute
+    }
+
+    /// A page table entry without any of R, W and X would be taken for a pointer to the next level
+    pub fn allows_nothing(self) -> bool {
+        !(self.read || self.write || self.execute)
+    }
+
+    /// User pages must never be both writable and executable (W^X),
+    /// so a bug that lets a process write to memory can't be turned into running code
+    pub fn violates_wx(self) -> bool {
+        self.user && self.write && self.execute
+    }
 
     fn as_raw(self) -> u64 {
         let mut flags = 0;
@@ -171,56 +311,1224 @@ impl PageFlags {
         flags |= if self.write { 1 } else { 0 } << 2;
         flags |= if self.execute { 1 } else { 0 } << 3;
         flags |= if self.user { 1 } else { 0 } << 4;
+        flags |= if self.global { 1 } else { 0 } << 5;
         flags
     }
 }
 
+/// How much memory is in use across the whole system
+pub fn mem_info() -> MemInfo {
+    let page = PAGE_SIZE as u64;
+    let heap = allocator::GLOBAL_ALLOCATOR.stats();
+    let (swap_total, swap_used) = swap::usage();
+    MemInfo {
+        total: frame::FRAME_ALLOCATOR.total_count() as u64 * page,
+        free: frame::FRAME_ALLOCATOR.free_count() as u64 * page,
+        heap_total: heap.total as u64,
+        heap_used: heap.used as u64,
+        page_tables: PAGE_TABLES.stats().in_use as u64 * page,
+        swap_total: swap_total as u64 * page,
+        swap_used: swap_used as u64 * page,
+    }
+}
+
+/// Physical memory ran out, even after swapping out whatever could be
+#[derive(Debug, Clone, Copy)]
+pub struct OutOfMemory;
+
+impl core::fmt::Display for OutOfMemory {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "Out of memory")
+    }
+}
+
+impl core::error::Error for OutOfMemory {}
+
+/// Allocate `n` contiguous, zeroed pages of physical memory
+/// PANICS: if physical memory is exhausted
 pub fn alloc_pages(n: usize) -> *mut u8 {
-    unsafe {
-        allocator::GLOBAL_ALLOCATOR
-            .alloc_zeroed(Layout::from_size_align(PAGE_SIZE * n, PAGE_SIZE).unwrap())
+    frame::alloc_frames(n).0
+}
+
+/// Allocate `n` contiguous, zeroed pages of physical memory, unless it has run out
+pub fn try_alloc_pages(n: usize) -> Result<*mut u8, OutOfMemory> {
+    frame::try_alloc_frames(n)
+        .map(|paddr| paddr.0)
+        .ok_or(OutOfMemory)
+}
+
+/// Allocate a zeroed page of the given size, aligned so it can be mapped as one leaf,
+/// unless memory has run out. Free it with `free_pages(page, size.frames())`.
+pub fn try_alloc_sized_page(size: PageSize) -> Result<*mut u8, OutOfMemory> {
+    frame::try_alloc_frames_aligned(size.frames(), size.frames())
+        .map(|paddr| paddr.0)
+        .ok_or(OutOfMemory)
+}
+
+/// Return `n` contiguous pages allocated by `alloc_pages`
+pub fn free_pages(pages: *mut u8, n: usize) {
+    frame::free_frames(Paddr(pages), n);
+}
+
+/// Sizes of page a leaf entry can map.
+/// Sv48 also has 512 GiB pages at level 3, which we never map.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PageSize {
+    /// 4 KiB page, mapped by a level 0 entry
+    Page,
+    /// 2 MiB megapage, mapped by a level 1 entry
+    Mega,
+    /// 1 GiB gigapage, mapped by a level 2 entry
+    Giga,
+}
+
+impl PageSize {
+    /// The page table level a leaf of this size lives at
+    pub fn level(self) -> usize {
+        match self {
+            PageSize::Page => 0,
+            PageSize::Mega => 1,
+            PageSize::Giga => 2,
+        }
+    }
+
+    pub fn from_level(level: usize) -> Self {
+        match level {
+            0 => PageSize::Page,
+            1 => PageSize::Mega,
+            2 => PageSize::Giga,
+            _ => panic!("No leaf pages at level {level}"),
+        }
+    }
+
+    pub fn bytes(self) -> usize {
+        PAGE_SIZE << (9 * self.level())
+    }
+
+    /// Number of 4 KiB frames a page of this size covers
+    pub fn frames(self) -> usize {
+        1 << (9 * self.level())
     }
 }
 
-unsafe fn walk(mut pagetable: *mut PTE, vaddr: Vaddr, alloc: bool) -> Option<*mut PTE> {
+/// Find the entry for `vaddr` at `level`, creating any missing tables on the way if `alloc` is set.
+/// Returns `None` if a table is missing and can't be allocated, or if a larger page already covers `vaddr`.
+unsafe fn walk(
+    mut pagetable: *mut PTE,
+    vaddr: Vaddr,
+    level: usize,
+    alloc: bool,
+) -> Option<*mut PTE> {
     unsafe {
-        for level in (1..=2).rev() {
-            let index = vaddr.pt_index_for_level(level);
+        for current in (level + 1..=top_level()).rev() {
+            let index = vaddr.pt_index_for_level(current);
             let pte = pagetable.add(index);
             if (*pte).valid() {
+                if (*pte).is_leaf() {
+                    return None;
+                }
                 pagetable = (*pte).into_paddr(pagetable);
             } else if alloc {
-                pagetable = allocator::GLOBAL_ALLOCATOR
-                    .alloc_zeroed(Layout::from_size_align(PAGE_SIZE, PAGE_SIZE).unwrap())
-                    as *mut PTE;
+                pagetable = try_alloc_page_table().ok()?;
                 *pte = PTE::from_paddr(pagetable as *mut u8).set_valid();
             } else {
                 return None;
             }
         }
-        Some(pagetable.add(vaddr.pt_index_for_level(0)))
+        Some(pagetable.add(vaddr.pt_index_for_level(level)))
     }
 }
 
-pub fn map_page(table1: *mut PTE, vaddr: Vaddr, paddr: Paddr, flags: PageFlags) {
-    if !vaddr.is_aligned() {
-        panic!("Virtual address not page-aligned");
+/// Find the leaf entry that maps `vaddr`, whatever size of page it maps
+unsafe fn find_leaf(mut pagetable: *mut PTE, vaddr: Vaddr) -> Option<(*mut PTE, PageSize)> {
+    unsafe {
+        for level in (0..=top_level()).rev() {
+            let pte = pagetable.add(vaddr.pt_index_for_level(level));
+            if !(*pte).valid() {
+                return None;
+            }
+            if (*pte).is_leaf() {
+                return Some((pte, PageSize::from_level(level)));
+            }
+            pagetable = (*pte).into_paddr(pagetable);
+        }
+        None
     }
-    if !is_aligned(paddr.0 as u64) {
-        panic!("Physical address not page-aligned");
+}
+
+fn map_page(
+    table1: *mut PTE,
+    vaddr: Vaddr,
+    paddr: Paddr,
+    flags: PageFlags,
+) -> Result<(), OutOfMemory> {
+    map_sized(table1, vaddr, paddr, flags, PageSize::Page)
+}
+
+/// Map a single page of the given size, both addresses must be aligned to that size.
+/// Fails if a table on the way to it is missing and there's no memory for it.
+fn map_sized(
+    table: *mut PTE,
+    vaddr: Vaddr,
+    paddr: Paddr,
+    flags: PageFlags,
+    size: PageSize,
+) -> Result<(), OutOfMemory> {
+    if vaddr.as_number() as usize % size.bytes() != 0 {
+        panic!("Virtual address not aligned to a {size:?} page");
+    }
+    if paddr.0 as usize % size.bytes() != 0 {
+        panic!("Physical address not aligned to a {size:?} page");
     }
 
     unsafe {
-        match walk(table1, vaddr, true) {
+        match walk(table, vaddr, size.level(), true) {
             Some(pte) if (*pte).valid() => panic!("remap"),
             Some(pte) => *pte = PTE::from_paddr(paddr.0).with_flags(flags).set_valid(),
-            None => unreachable!(),
+            None if find_leaf(table, vaddr).is_some() => panic!("remap inside a larger page"),
+            None => return Err(OutOfMemory),
+        }
+    }
+    Ok(())
+}
+
+/// Map `len` bytes starting at `vaddr` to the memory starting at `paddr`,
+/// using the largest pages that the alignment of both allows.
+/// If memory runs out part of the range may have been mapped, which is left to the caller to undo.
+fn map_range(
+    table: *mut PTE,
+    vaddr: Vaddr,
+    paddr: Paddr,
+    len: usize,
+    flags: PageFlags,
+) -> Result<(), OutOfMemory> {
+    let mut offset = 0;
+    while offset < len {
+        let virt = vaddr.as_number() as usize + offset;
+        let phys = paddr.0 as usize + offset;
+        let size = [PageSize::Giga, PageSize::Mega, PageSize::Page]
+            .into_iter()
+            .find(|size| {
+                virt % size.bytes() == 0 && phys % size.bytes() == 0 && offset + size.bytes() <= len
+            })
+            .expect("Range not page-aligned");
+        map_sized(
+            table,
+            Vaddr(virt as u64),
+            Paddr(phys as *mut u8),
+            flags,
+            size,
+        )?;
+        offset += size.bytes();
+    }
+    Ok(())
+}
+
+/// Remove the mapping for `vaddr`, returning the frame it pointed to and the size of the page.
+/// Returns `None` if nothing was mapped there.
+/// The frame is not freed and the TLB is not flushed, that's up to the caller.
+fn unmap_page(table: *mut PTE, vaddr: Vaddr) -> Option<(Paddr, PageSize)> {
+    if !vaddr.is_aligned() {
+        panic!("Virtual address not page-aligned");
+    }
+
+    unsafe {
+        let (pte, size) = find_leaf(table, vaddr)?;
+        if vaddr.as_number() as usize % size.bytes() != 0 {
+            panic!("Unmapping part of a {size:?} page");
+        }
+        let paddr = (*pte).paddr();
+        *pte = PTE::zero();
+        Some((paddr, size))
+    }
+}
+
+/// Change the permissions of the existing mapping for `vaddr`.
+/// Returns false if nothing was mapped there.
+/// The TLB is not flushed, that's up to the caller.
+fn protect_page(table: *mut PTE, vaddr: Vaddr, flags: PageFlags) -> bool {
+    if !vaddr.is_aligned() {
+        panic!("Virtual address not page-aligned");
+    }
+
+    unsafe {
+        match find_leaf(table, vaddr) {
+            Some((pte, size)) => {
+                if vaddr.as_number() as usize % size.bytes() != 0 {
+                    panic!("Protecting part of a {size:?} page");
+                }
+                // A copy-on-write page must stay read-only until it's been copied
+                let flags = if (*pte).cow() {
+                    PageFlags {
+                        write: false,
+                        ..flags
+                    }
+                } else {
+                    flags
+                };
+                *pte = (*pte).with_permissions(flags);
+                true
+            }
+            None => false,
         }
     }
 }
 
-fn is_aligned(value: u64) -> bool {
-    value % 4096 == 0
+/// Tear down a whole page table: every intermediate table is returned to the cache,
+/// and every user frame is returned to the frame allocator.
+/// Kernel mappings only point at memory the process never owned, so their frames are kept.
+/// SAFETY: the table must not be active or referenced anywhere else
+unsafe fn free_address_space(root: *mut PTE) {
+    unsafe {
+        free_table(root, top_level());
+    }
+}
+
+unsafe fn free_table(table: *mut PTE, level: usize) {
+    unsafe {
+        for index in 0..512 {
+            let pte = *table.add(index);
+            if let Some(slot) = pte.swap_slot() {
+                swap::release(slot);
+                continue;
+            }
+            // Global entries are the kernel's, and are shared with every other process
+            if !pte.valid() || pte.global() {
+                continue;
+            }
+            if !pte.is_leaf() {
+                // Level 0 entries are always leaves, so this can't underflow
+                free_table(pte.into_paddr(table), level - 1);
+            } else if pte.u() {
+                free_pages(pte.paddr().0, PageSize::from_level(level).frames());
+            }
+        }
+        free_page_table(table);
+    }
+}
+
+/// Call `f` with every leaf entry in `table`, along with the virtual address and size of page it maps.
+/// Entries of swapped-out pages are included, shared kernel mappings are skipped.
+unsafe fn for_each_leaf(
+    table: *mut PTE,
+    level: usize,
+    base: u64,
+    f: &mut impl FnMut(Vaddr, *mut PTE, PageSize),
+) {
+    unsafe {
+        for index in 0..512 {
+            let pte = *table.add(index);
+            let vaddr = base | ((index as u64) << (12 + level * 9));
+            if pte.swap_slot().is_some() {
+                f(Vaddr(vaddr), table.add(index), PageSize::from_level(level));
+                continue;
+            }
+            if !pte.valid() || pte.global() {
+                continue;
+            }
+            if pte.is_leaf() {
+                f(Vaddr(vaddr), table.add(index), PageSize::from_level(level));
+            } else {
+                for_each_leaf(pte.into_paddr(table), level - 1, vaddr, f);
+            }
+        }
+    }
+}
+
+/// Append the pages mapped under `table` to `maps`, merging each page into the last range
+/// when it follows on from it with the same flags.
+/// Shared kernel mappings are only included if `global` is set.
+unsafe fn collect_mappings(
+    table: *mut PTE,
+    level: usize,
+    base: u64,
+    global: bool,
+    maps: &mut Vec<Mapping>,
+) {
+    unsafe {
+        for index in 0..512 {
+            let pte = *table.add(index);
+            let vaddr = base | ((index as u64) << (12 + level * 9));
+            if !pte.valid() || (pte.global() && !global) {
+                continue;
+            }
+            if !pte.is_leaf() {
+                collect_mappings(pte.into_paddr(table), level - 1, vaddr, global, maps);
+                continue;
+            }
+            let start = canonical(vaddr);
+            let end = start + PageSize::from_level(level).bytes() as u64;
+            let flags = pte.mapping_flags();
+            match maps.last_mut() {
+                Some(last) if last.end == start && last.flags == flags => last.end = end,
+                _ => maps.push(Mapping { start, end, flags }),
+            }
+        }
+    }
+}
+
+/// Call `f` with each 4 KiB user page mapped in `table` that lies in `from..to`, lowest first,
+/// until it returns true. Returns the address of the page it stopped at.
+unsafe fn find_page(
+    table: *mut PTE,
+    level: usize,
+    base: u64,
+    from: u64,
+    to: u64,
+    f: &mut impl FnMut(Vaddr, *mut PTE) -> bool,
+) -> Option<Vaddr> {
+    unsafe {
+        let span = 1 << (12 + level * 9);
+        for index in 0..512 {
+            let vaddr = base | ((index as u64) << (12 + level * 9));
+            if vaddr + span <= from {
+                continue;
+            }
+            if vaddr >= to {
+                break;
+            }
+            let pte = *table.add(index);
+            if !pte.valid() || pte.global() {
+                continue;
+            }
+            if !pte.is_leaf() {
+                let found = find_page(pte.into_paddr(table), level - 1, vaddr, from, to, f);
+                if found.is_some() {
+                    return found;
+                }
+            } else if level == 0 && pte.u() && f(Vaddr(vaddr), table.add(index)) {
+                return Some(Vaddr(vaddr));
+            }
+        }
+        None
+    }
+}
+
+/// Copy the highest bit of a virtual address the paging mode uses into the bits above it,
+/// as the hardware requires
+fn canonical(vaddr: u64) -> u64 {
+    let unused = 64 - paging_mode().va_bits();
+    (((vaddr << unused) as i64) >> unused) as u64
+}
+
+/// The paging modes the kernel can run in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PagingMode {
+    /// Three levels of page table, for 39 bit virtual addresses
+    Sv39,
+    /// Four levels of page table, for 48 bit virtual addresses
+    Sv48,
+}
+
+impl PagingMode {
+    pub fn levels(self) -> usize {
+        match self {
+            PagingMode::Sv39 => 3,
+            PagingMode::Sv48 => 4,
+        }
+    }
+
+    /// The MODE field of `satp` that selects this mode
+    pub fn satp_mode(self) -> u64 {
+        match self {
+            PagingMode::Sv39 => 8,
+            PagingMode::Sv48 => 9,
+        }
+    }
+
+    /// Number of bits of a virtual address that get translated
+    pub fn va_bits(self) -> u32 {
+        12 + 9 * self.levels() as u32
+    }
+}
+
+/// Number of page table levels of the paging mode picked at boot
+static PAGING_LEVELS: AtomicUsize = AtomicUsize::new(3);
+
+/// The paging mode the kernel runs in, picked at boot
+pub fn paging_mode() -> PagingMode {
+    match PAGING_LEVELS.load(Ordering::Relaxed) {
+        4 => PagingMode::Sv48,
+        _ => PagingMode::Sv39,
+    }
+}
+
+/// The level of the root page table
+fn top_level() -> usize {
+    paging_mode().levels() - 1
+}
+
+/// Root of the kernel's page table, built once at boot
+static KERNEL_ROOT: AtomicPtr<PTE> = AtomicPtr::new(core::ptr::null_mut());
+
+/// Build the kernel's mappings and switch to them.
+/// All of RAM is identity mapped, with permissions by section, along with the virtio registers.
+/// Every table that only holds kernel mappings is marked global,
+/// so that address spaces can share it instead of building their own copy.
+/// Paging uses Sv48 if the hart supports it, unless the `no4lvl` boot argument asks for Sv39.
+pub fn init_kernel_space() {
+    let modes = if fdt::boot_info().has_arg("no4lvl") {
+        &[PagingMode::Sv39][..]
+    } else {
+        &[PagingMode::Sv48, PagingMode::Sv39][..]
+    };
+    // Writing a mode the hart doesn't support to satp has no effect at all, which leaves paging off.
+    // So each mode is tried with a table built for it, until one sticks.
+    let (root, mode) = modes
+        .iter()
+        .find_map(|&mode| {
+            PAGING_LEVELS.store(mode.levels(), Ordering::Relaxed);
+            let root = build_kernel_table();
+            let satp = asid::satp(mode.satp_mode(), 0, root as usize / PAGE_SIZE);
+            unsafe {
+                core::arch::asm!("sfence.vma", "csrw satp, {satp}", "sfence.vma", satp = in(reg) satp)
+            };
+            if read_csr!("satp") >> 60 == mode.satp_mode() {
+                Some((root, mode))
+            } else {
+                unsafe { free_address_space(root) };
+                None
+            }
+        })
+        .expect("Hart supports none of our paging modes");
+    println!("Paging mode: {mode:?}");
+    // Only mark tables global once they're the ones in use, as freeing a table skips global entries
+    unsafe {
+        mark_global(root, top_level(), 0);
+        core::arch::asm!("sfence.vma");
+    }
+    KERNEL_ROOT.store(root, Ordering::Relaxed);
+}
+
+/// Build the kernel's page table for the current paging mode
+fn build_kernel_table() -> *mut PTE {
+    let root = alloc_page_table();
+    let start = &raw mut constants::__kernel_start as usize;
+    let text_end = &raw mut constants::__text_end as usize;
+    let rodata_end = &raw mut constants::__rodata_end as usize;
+    let end = fdt::boot_info().ram.end() as usize;
+    // The kernel lives in low memory, and each page points to the numerically same frame.
+    // Code is only executable and nothing else is, read-only data is just that,
+    // and everything from the data on (including the heap and all free memory) is read-write.
+    // RAM is megapage aligned, so the bulk of it takes only a handful of entries.
+    let sections = [
+        (start, text_end, PageFlags::default().read().execute()),
+        (text_end, rodata_end, PageFlags::default().read()),
+        (rodata_end, end, PageFlags::default().read().write()),
+    ];
+    for (from, to, flags) in sections {
+        map_range(
+            root,
+            Vaddr(from as u64),
+            Paddr(from as *mut u8),
+            to - from,
+            flags.global(),
+        )
+        .expect("Out of memory building the kernel page table");
+    }
+    for device in [VIRTIO_BLK_PADDR, VIRTIO_SWAP_PADDR] {
+        map_page(
+            root,
+            Vaddr(device),
+            Paddr(device as *mut u8),
+            PageFlags::default().read().write().global(),
+        )
+        .expect("Out of memory building the kernel page table");
+    }
+    // Kernel stacks come and go with processes. Their table has to exist before any address space
+    // copies the kernel's mappings, so that it's shared and they all see the stacks.
+    unsafe { walk(root, Vaddr(KERNEL_STACKS_BASE), 1, true) }
+        .expect("Out of memory building the kernel page table");
+    root
+}
+
+/// Distance between the kernel stacks of neighbouring slots, including the guard page
+const KERNEL_STACK_STRIDE: usize = KERNEL_STACK_SIZE + PAGE_SIZE;
+
+/// The kernel stack of a process, mapped into the shared kernel mappings with an unmapped guard
+/// page below it, so that overflowing it faults rather than corrupting whatever lies below.
+/// The stack is unmapped and its frames freed on drop.
+#[derive(Debug)]
+pub struct KernelStack {
+    slot: usize,
+    frames: Paddr,
+}
+
+impl KernelStack {
+    /// Allocate and map the stack for process slot `slot`
+    pub fn new(slot: usize) -> Result<Self, OutOfMemory> {
+        // The stacks all live under the one table that's shared from boot
+        assert!(
+            (slot + 1) * KERNEL_STACK_STRIDE <= PageSize::Giga.bytes(),
+            "Out of kernel stack slots"
+        );
+        let frames = frame::try_alloc_frames(KERNEL_STACK_SIZE / PAGE_SIZE).ok_or(OutOfMemory)?;
+        let stack = Self { slot, frames };
+        // If this fails, dropping the stack unmaps whatever part of it did get mapped
+        map_range(
+            KERNEL_ROOT.load(Ordering::Relaxed),
+            Vaddr(stack.bottom()),
+            frames,
+            KERNEL_STACK_SIZE,
+            PageFlags::default().read().write().global(),
+        )?;
+        Ok(stack)
+    }
+
+    fn bottom(&self) -> u64 {
+        KERNEL_STACKS_BASE + (self.slot * KERNEL_STACK_STRIDE + PAGE_SIZE) as u64
+    }
+
+    /// The initial stack pointer
+    pub fn top(&self) -> u64 {
+        self.bottom() + KERNEL_STACK_SIZE as u64
+    }
+
+    /// Find the slot whose guard page contains `vaddr`, if any
+    pub fn guard_slot(vaddr: u64) -> Option<usize> {
+        let offset = vaddr.checked_sub(KERNEL_STACKS_BASE)? as usize;
+        (offset % KERNEL_STACK_STRIDE < PAGE_SIZE).then_some(offset / KERNEL_STACK_STRIDE)
+    }
+}
+
+impl Drop for KernelStack {
+    fn drop(&mut self) {
+        let root = KERNEL_ROOT.load(Ordering::Relaxed);
+        let mut offset = 0;
+        while offset < KERNEL_STACK_SIZE {
+            let vaddr = Vaddr(self.bottom() + offset as u64);
+            // The rest of a stack that couldn't be mapped in full is missing
+            let Some((_, size)) = unmap_page(root, vaddr) else {
+                break;
+            };
+            // Global mappings are cached for every ASID
+            flush_tlb(vaddr, None);
+            offset += size.bytes();
+        }
+        free_pages(self.frames.0, KERNEL_STACK_SIZE / PAGE_SIZE);
+    }
+}
+
+/// Set the global bit on every table entry that only covers kernel addresses
+unsafe fn mark_global(table: *mut PTE, level: usize, base: u64) {
+    unsafe {
+        for index in 0..512 {
+            let pte = table.add(index);
+            if !(*pte).valid() || (*pte).is_leaf() {
+                continue;
+            }
+            let vaddr = base | ((index as u64) << (12 + level * 9));
+            if vaddr >= USER_END {
+                *pte = PTE((*pte).0 | (1 << 5));
+            } else {
+                mark_global((*pte).into_paddr(table), level - 1, vaddr);
+            }
+        }
+    }
+}
+
+/// Copy the kernel's mappings into a fresh table.
+/// Global tables are shared by pointer, the rest are copied so user mappings can be added next to them.
+/// If memory runs out, the entries copied so far are left in place for `free_table` to clean up.
+unsafe fn copy_kernel_table(table: *mut PTE, kernel: *mut PTE) -> Result<(), OutOfMemory> {
+    unsafe {
+        for index in 0..512 {
+            let pte = *kernel.add(index);
+            if pte.valid() && !pte.global() && !pte.is_leaf() {
+                let copy = try_alloc_page_table()?;
+                // Linked in before it's filled, so it's freed along with the rest on failure
+                *table.add(index) = PTE(PTE::from_paddr(copy as *mut u8).0 | (pte.0 & FLAG_BITS));
+                copy_kernel_table(copy, pte.into_paddr(kernel))?;
+            } else {
+                *table.add(index) = pte;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The virtual address space of a process.
+/// Owns its page tables and every user page mapped into it, all of which are freed on drop.
+#[derive(Debug)]
+pub struct AddressSpace {
+    root: *mut PTE,
+    /// Tags this address space's TLB entries, assigned the first time it's switched to
+    asid: Option<Asid>,
+    /// The areas the process may access, sorted by address
+    vmas: Vec<Vma>,
+    /// Where the heap starts, and the current end of it (the program break)
+    heap_start: u64,
+    brk: u64,
+}
+
+impl AddressSpace {
+    /// Create an address space with nothing but the kernel mapped
+    pub fn new() -> Result<Self, OutOfMemory> {
+        let kernel = KERNEL_ROOT.load(Ordering::Relaxed);
+        assert!(!kernel.is_null(), "Kernel address space not initialized");
+        let space = Self {
+            root: try_alloc_page_table()?,
+            asid: None,
+            vmas: Vec::new(),
+            heap_start: 0,
+            brk: 0,
+        };
+        // On failure, dropping the address space frees the tables copied so far
+        unsafe { copy_kernel_table(space.root, kernel)? };
+        Ok(space)
+    }
+
+    /// Place the heap at `start`, empty to begin with
+    pub fn init_heap(&mut self, start: Vaddr) {
+        assert!(start.is_aligned(), "Heap not page-aligned");
+        self.heap_start = start.as_number();
+        self.brk = start.as_number();
+    }
+
+    /// Move the program break by `increment` bytes and return the old break,
+    /// or None if the heap can't be moved there.
+    /// Memory the heap grows into is zero-filled on first touch, memory it shrinks out of is freed.
+    pub fn sbrk(&mut self, increment: i64) -> Option<u64> {
+        let old = self.brk;
+        let new = old.checked_add_signed(increment)?;
+        if self.heap_start == 0 || new < self.heap_start || new > USER_HEAP_END {
+            return None;
+        }
+        let page_align = |addr: u64| addr.next_multiple_of(PAGE_SIZE as u64);
+        let (old_end, new_end) = (page_align(old), page_align(new));
+        let collides = self.vmas.iter().any(|vma| {
+            !matches!(vma.kind, VmaKind::Heap) && vma.overlaps(self.heap_start, new_end)
+        });
+        if collides {
+            return None;
+        }
+        for page in (new_end..old_end).step_by(PAGE_SIZE) {
+            // Pages that were never touched aren't mapped
+            self.unmap(Vaddr(page));
+        }
+        self.vmas.retain(|vma| !matches!(vma.kind, VmaKind::Heap));
+        if new_end > self.heap_start {
+            let flags = PageFlags::default().read().write().user();
+            self.add_vma(Vma::new(self.heap_start, new_end, flags, VmaKind::Heap));
+        }
+        self.brk = new;
+        Some(old)
+    }
+
+    /// Make `vma` part of the address space.
+    /// Pages of an `Image` area must be mapped by the caller, other areas are filled in on demand.
+    /// PANICS: if `vma` overlaps an existing area
+    pub fn add_vma(&mut self, vma: Vma) {
+        assert!(
+            !vma.flags.violates_wx(),
+            "Writable and executable VMA {:#x} - {:#x}",
+            vma.start,
+            vma.end
+        );
+        assert!(
+            !self
+                .vmas
+                .iter()
+                .any(|other| other.overlaps(vma.start, vma.end)),
+            "Overlapping VMA {:#x} - {:#x}",
+            vma.start,
+            vma.end
+        );
+        let index = self.vmas.partition_point(|other| other.start < vma.start);
+        self.vmas.insert(index, vma);
+    }
+
+    /// Does any area overlap the range from `start` to `end`
+    pub fn overlaps(&self, start: u64, end: u64) -> bool {
+        self.vmas.iter().any(|vma| vma.overlaps(start, end))
+    }
+
+    /// Find the area containing `vaddr`
+    pub fn find_vma(&self, vaddr: Vaddr) -> Option<&Vma> {
+        self.vmas.iter().find(|vma| vma.contains(vaddr.as_number()))
+    }
+
+    /// Give this address space a private, writable copy of the copy-on-write page at `vaddr`
+    fn copy_on_write(&mut self, vaddr: Vaddr) -> Result<(), OutOfMemory> {
+        unsafe {
+            let (pte, size) = find_leaf(self.root, vaddr).expect("No page to copy");
+            let old = (*pte).paddr();
+            if frame::FRAME_ALLOCATOR.ref_count(old) > 1 {
+                let copy = try_alloc_sized_page(size)?;
+                core::ptr::copy_nonoverlapping(old.0, copy, size.bytes());
+                *pte = PTE(PTE::from_paddr(copy).0 | ((*pte).0 & FLAG_BITS));
+                free_pages(old.0, size.frames());
+            }
+            // Otherwise everyone else has let go of the page, and it can be written in place
+            *pte = (*pte).without_cow();
+        }
+        self.flush(vaddr);
+        Ok(())
+    }
+
+    /// Make a copy-on-write copy of this address space, as `fork` does.
+    /// Both copies share the user pages read-only until either writes to one, which makes it a
+    /// private copy. Shared memory is left shared and writable.
+    /// If memory runs out the half-built copy is thrown away again.
+    pub fn fork(&mut self) -> Result<Self, OutOfMemory> {
+        let mut child = Self::new()?;
+        child.vmas = self.vmas.clone();
+        child.heap_start = self.heap_start;
+        child.brk = self.brk;
+        let vmas = &self.vmas;
+        let mut result = Ok(());
+        unsafe {
+            for_each_leaf(self.root, top_level(), 0, &mut |vaddr, pte, size| {
+                if result.is_err() {
+                    return;
+                }
+                if !(*pte).u() && (*pte).swap_slot().is_none() {
+                    // Kernel mappings were already copied by `new`
+                    return;
+                }
+                // References are only taken once the child has the entry, so dropping it undoes them
+                let Some(entry) = walk(child.root, vaddr, size.level(), true) else {
+                    result = Err(OutOfMemory);
+                    return;
+                };
+                if let Some(slot) = (*pte).swap_slot() {
+                    // Each of them gets a private copy when it reads the page back in
+                    swap::share(slot);
+                    *entry = *pte;
+                    return;
+                }
+                let shared = vmas.iter().any(|vma| {
+                    vma.contains(vaddr.as_number()) && matches!(vma.kind, VmaKind::Shared)
+                });
+                // Read-only pages too, or making them writable later would write to the other's
+                if !shared {
+                    *pte = (*pte).with_cow();
+                }
+                frame::FRAME_ALLOCATOR.share((*pte).paddr(), size.frames());
+                *entry = *pte;
+            });
+        }
+        // Pages that just became read-only may still be writable in the TLB
+        flush_tlb_all(asid::current_value(self.asid));
+        result.map(|()| child)
+    }
+
+    /// Create a new area of `len` bytes, at `fixed` if given or wherever it fits in the mmap range otherwise.
+    /// Returns the start of the area, or None if there's no room for it or `flags` break W^X.
+    /// Shared memory is mapped right away, so it must allow some access too.
+    pub fn mmap(
+        &mut self,
+        fixed: Option<Vaddr>,
+        len: u64,
+        flags: PageFlags,
+        kind: VmaKind,
+    ) -> Option<Vaddr> {
+        let len = len.checked_next_multiple_of(PAGE_SIZE as u64)?;
+        let eager = matches!(kind, VmaKind::Shared);
+        if len == 0 || flags.violates_wx() || (eager && flags.allows_nothing()) {
+            return None;
+        }
+        let start = match fixed {
+            Some(addr) => {
+                let (start, end) = mmap_range(addr, len)?;
+                if self.vmas.iter().any(|vma| vma.overlaps(start, end)) {
+                    return None;
+                }
+                start
+            }
+            None => self.find_free_range(len)?,
+        };
+        self.add_vma(Vma::new(start, start + len, flags, kind));
+        Some(Vaddr(start))
+    }
+
+    /// Remove everything mapped in `len` bytes from `start` in the mmap range, freeing the pages.
+    /// Returns false if the range isn't page-aligned or reaches outside the mmap range.
+    pub fn munmap(&mut self, start: Vaddr, len: u64) -> bool {
+        let Some((start, end)) = mmap_range(start, len) else {
+            return false;
+        };
+        self.split_vma(start);
+        self.split_vma(end);
+        self.vmas.retain(|vma| !vma.overlaps(start, end));
+        for page in (start..end).step_by(PAGE_SIZE) {
+            // Pages that were never touched aren't mapped
+            self.unmap(Vaddr(page));
+        }
+        true
+    }
+
+    /// Change the permissions of `len` bytes from `start` in the mmap range.
+    /// Returns false if the range isn't page-aligned, reaches outside the mmap range or isn't fully mapped,
+    /// if it would make file-backed pages writable, or if `flags` allow no access at all or break W^X.
+    pub fn mprotect(&mut self, start: Vaddr, len: u64, flags: PageFlags) -> bool {
+        let Some((start, end)) = mmap_range(start, len) else {
+            return false;
+        };
+        if flags.allows_nothing() || flags.violates_wx() {
+            return false;
+        }
+        let mut covered = start;
+        for vma in self.vmas.iter().filter(|vma| vma.overlaps(start, end)) {
+            let file = matches!(vma.kind, VmaKind::File(_));
+            if vma.start > covered || (file && flags.can_write()) {
+                return false;
+            }
+            covered = vma.end;
+        }
+        if covered < end {
+            return false;
+        }
+        self.split_vma(start);
+        self.split_vma(end);
+        for vma in self.vmas.iter_mut().filter(|vma| vma.overlaps(start, end)) {
+            vma.flags = flags;
+        }
+        for page in (start..end).step_by(PAGE_SIZE) {
+            self.protect(Vaddr(page), flags);
+        }
+        true
+    }
+
+    /// Find the lowest gap of `len` bytes in the mmap range
+    fn find_free_range(&self, len: u64) -> Option<u64> {
+        // Bigger than the whole range can't fit, and would overflow the sums below
+        if len > USER_MMAP_END - USER_MMAP_BASE {
+            return None;
+        }
+        let mut start = USER_MMAP_BASE;
+        for vma in self.vmas.iter().filter(|vma| vma.end > USER_MMAP_BASE) {
+            if vma.start >= start + len {
+                break;
+            }
+            start = start.max(vma.end);
+        }
+        (start + len <= USER_MMAP_END).then_some(start)
+    }
+
+    /// Make sure no area straddles `at`, splitting the one that does
+    fn split_vma(&mut self, at: u64) {
+        let straddling = self
+            .vmas
+            .iter()
+            .position(|vma| vma.start < at && at < vma.end);
+        if let Some(index) = straddling {
+            let (head, tail) = self.vmas[index].split_at(at);
+            self.vmas[index] = head;
+            self.vmas.insert(index + 1, tail);
+        }
+    }
+
+    /// Make sure the page at `vaddr` is mapped as a user page that allows `access`,
+    /// faulting it in (or copying it, if it's copy-on-write) just like the process touching it would.
+    pub fn fault_in(&mut self, vaddr: Vaddr, access: Access) -> Result<(), FaultError> {
+        if vaddr.as_number() >= USER_END {
+            return Err(FaultError::Unmapped(vaddr.as_number()));
+        }
+        let allowed = |pte: PTE| match access {
+            Access::Read => pte.read(),
+            Access::Write => pte.write(),
+            Access::Execute => pte.x(),
+        };
+        match self.lookup(vaddr) {
+            Some((pte, _)) if pte.u() && allowed(pte) => Ok(()),
+            _ => self.handle_fault(vaddr, access),
+        }
+    }
+
+    /// Resolve a page fault at `vaddr`, by mapping in a zeroed frame if the area allows it.
+    /// An error means the access was a genuine bad one.
+    pub fn handle_fault(&mut self, vaddr: Vaddr, access: Access) -> Result<(), FaultError> {
+        let fault = vaddr.as_number();
+        let vma = *self.find_vma(vaddr).ok_or(FaultError::Unmapped(fault))?;
+        if !vma.allows(access) {
+            return Err(FaultError::PermissionDenied(fault, access));
+        }
+        let page = Vaddr(fault & !(PAGE_SIZE as u64 - 1));
+        let out_of_memory = |_: OutOfMemory| FaultError::OutOfMemory(fault);
+        if let Some(slot) = self.swapped(page) {
+            let frame = try_alloc_pages(1).map_err(out_of_memory)?;
+            if swap::read(slot, Paddr(frame)).is_err() {
+                free_pages(frame, 1);
+                return Err(FaultError::SwapIn(fault));
+            }
+            // The swapped entry isn't valid, so mapping simply replaces it.
+            // Nothing else holds the contents now, so they have to be saved again to swap the page out.
+            self.map_faulted(page, Paddr(frame), vma.flags, true)
+                .map_err(out_of_memory)?;
+            swap::release(slot);
+            return Ok(());
+        }
+        if let Some((pte, _)) = self.lookup(page) {
+            if access == Access::Write && pte.cow() {
+                return self.copy_on_write(page).map_err(out_of_memory);
+            }
+            // The page is there, so the entry itself forbids this access
+            return Err(FaultError::PermissionDenied(fault, access));
+        }
+        match vma.kind {
+            VmaKind::Heap | VmaKind::Stack | VmaKind::Anonymous => {
+                let frame = try_alloc_pages(1).map_err(out_of_memory)?;
+                self.map_faulted(page, Paddr(frame), vma.flags, false)
+                    .map_err(out_of_memory)
+            }
+            VmaKind::File(file) => {
+                let frame = try_alloc_pages(1).map_err(out_of_memory)?;
+                let offset = file.offset + (page.as_number() - vma.start) as usize;
+                // Whatever lies past the end of the file reads as zero
+                if offset < file.data.len() {
+                    let len = (file.data.len() - offset).min(PAGE_SIZE);
+                    unsafe {
+                        core::ptr::copy_nonoverlapping(file.data[offset..].as_ptr(), frame, len)
+                    };
+                }
+                self.map_faulted(page, Paddr(frame), vma.flags, false)
+                    .map_err(out_of_memory)
+            }
+            // These are mapped in full along with the area, so a missing page was unmapped
+            VmaKind::Image | VmaKind::Shared => Err(FaultError::Unmapped(fault)),
+        }
+    }
+
+    /// Map the page at `vaddr` to the frame at `paddr`.
+    /// Frames mapped with `PageFlags::user` become owned by the address space, but only once
+    /// this succeeds: if there's no memory for the page table, the frame is still the caller's.
+    /// PANICS: if `vaddr` is already mapped, or `flags` break W^X
+    pub fn map(&mut self, vaddr: Vaddr, paddr: Paddr, flags: PageFlags) -> Result<(), OutOfMemory> {
+        assert!(!flags.violates_wx(), "Writable and executable user page");
+        map_page(self.root, vaddr, paddr, flags)?;
+        self.flush(vaddr);
+        Ok(())
+    }
+
+    /// Map a page that's being faulted in. It's marked accessed, as it's about to be, so the clock
+    /// doesn't pick it to swap out straight away. It's marked dirty if faulting it in again
+    /// wouldn't recreate what's in it. The frame is freed if it can't be mapped.
+    fn map_faulted(
+        &mut self,
+        vaddr: Vaddr,
+        paddr: Paddr,
+        flags: PageFlags,
+        dirty: bool,
+    ) -> Result<(), OutOfMemory> {
+        if let Err(err) = self.map(vaddr, paddr, flags) {
+            free_pages(paddr.0, 1);
+            return Err(err);
+        }
+        unsafe {
+            let (pte, _) = find_leaf(self.root, vaddr).unwrap();
+            *pte = PTE((*pte).0 | ACCESSED_BIT | if dirty { DIRTY_BIT } else { 0 });
+        }
+        self.flush(vaddr);
+        Ok(())
+    }
+
+    /// Remove the mapping for the page at `vaddr`, freeing the frames if it was a user page,
+    /// or its swap slot if it was swapped out.
+    /// Returns false if nothing was mapped there.
+    pub fn unmap(&mut self, vaddr: Vaddr) -> bool {
+        if let Some(slot) = self.swapped(vaddr) {
+            unsafe { *walk(self.root, vaddr, 0, false).unwrap() = PTE::zero() };
+            swap::release(slot);
+            return true;
+        }
+        let user = self.lookup(vaddr).is_some_and(|(pte, _)| pte.u());
+        match unmap_page(self.root, vaddr) {
+            Some((paddr, size)) => {
+                self.flush(vaddr);
+                if user {
+                    free_pages(paddr.0, size.frames());
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Change the permissions of the page at `vaddr`.
+    /// Returns false if nothing was mapped there.
+    pub fn protect(&mut self, vaddr: Vaddr, flags: PageFlags) -> bool {
+        let changed = protect_page(self.root, vaddr, flags);
+        if changed {
+            self.flush(vaddr);
+        }
+        changed
+    }
+
+    /// The swap slot holding the page at `vaddr`, if it was swapped out
+    fn swapped(&self, vaddr: Vaddr) -> Option<usize> {
+        unsafe { walk(self.root, vaddr, 0, false).and_then(|pte| (*pte).swap_slot()) }
+    }
+
+    /// Sweep the clock hand over the user pages in `from..to`, looking for one to swap out.
+    /// A page that was accessed since the hand last passed it gets a second chance: its accessed bit
+    /// is cleared and it's passed over. Pages shared with anyone else are never picked, and pages
+    /// that would have to be written to swap only if `dirty` is set.
+    /// Returns the first page that qualifies.
+    pub fn find_victim(&mut self, from: u64, to: u64, dirty: bool) -> Option<Vaddr> {
+        let asid = asid::current_value(self.asid);
+        unsafe {
+            find_page(self.root, top_level(), 0, from, to, &mut |vaddr, pte| {
+                let Some(vma) = self.find_vma(vaddr) else {
+                    return false;
+                };
+                if matches!(vma.kind, VmaKind::Shared)
+                    || frame::FRAME_ALLOCATOR.ref_count((*pte).paddr()) > 1
+                    || !(dirty || self.is_clean(vaddr))
+                {
+                    return false;
+                }
+                if (*pte).accessed() {
+                    *pte = (*pte).without_accessed();
+                    flush_tlb(vaddr, asid);
+                    return false;
+                }
+                true
+            })
+        }
+    }
+
+    /// Can the page at `vaddr` be dropped without saving it, because faulting it in again
+    /// recreates it exactly? That's the case for pages filled in on demand that haven't been
+    /// written to since. Image pages have nothing to be filled in from, so they're never clean.
+    pub fn is_clean(&self, vaddr: Vaddr) -> bool {
+        let demand = self.find_vma(vaddr).is_some_and(|vma| {
+            matches!(
+                vma.kind,
+                VmaKind::Heap | VmaKind::Stack | VmaKind::Anonymous | VmaKind::File(_)
+            )
+        });
+        demand && self.lookup(vaddr).is_some_and(|(pte, _)| !pte.dirty())
+    }
+
+    /// Take the user page at `vaddr` out of memory and free its frame.
+    /// If it was saved to swap `slot`, the entry remembers the slot so the next access reads it back.
+    /// Otherwise the page must be clean, and the next access fills it in from scratch.
+    pub fn swap_out(&mut self, vaddr: Vaddr, slot: Option<usize>) {
+        let Some(slot) = slot else {
+            self.unmap(vaddr);
+            return;
+        };
+        unsafe {
+            let (pte, _) = find_leaf(self.root, vaddr).expect("Swapping out an unmapped page");
+            let frame = (*pte).paddr();
+            *pte = PTE::swapped(slot);
+            self.flush(vaddr);
+            free_pages(frame.0, 1);
+        }
+    }
+
+    /// Flush the TLB entry for `vaddr` in this address space only
+    fn flush(&self, vaddr: Vaddr) {
+        flush_tlb(vaddr, asid::current_value(self.asid));
+    }
+
+    /// Translate a virtual address to the physical address it maps to
+    pub fn translate(&self, vaddr: Vaddr) -> Option<Paddr> {
+        self.lookup(vaddr).map(|(pte, size)| {
+            let offset = vaddr.as_number() as usize & (size.bytes() - 1);
+            Paddr(pte.paddr().0.wrapping_add(offset))
+        })
+    }
+
+    /// How much memory this address space takes up. The pid is left for the caller to fill in.
+    pub fn mem_usage(&self) -> ProcMemInfo {
+        let mut usage = ProcMemInfo {
+            virtual_size: self.vmas.iter().map(|vma| vma.end - vma.start).sum(),
+            ..ProcMemInfo::default()
+        };
+        unsafe {
+            for_each_leaf(self.root, top_level(), 0, &mut |_, pte, size| {
+                if (*pte).swap_slot().is_some() {
+                    usage.swapped += PAGE_SIZE as u64;
+                } else if (*pte).u() {
+                    usage.resident += size.bytes() as u64;
+                }
+            });
+        }
+        usage
+    }
+
+    /// The pages mapped in this address space, merged into ranges with the same flags, lowest first.
+    /// The kernel's own mappings, which every address space shares, are only included if `kernel` is set.
+    pub fn mappings(&self, kernel: bool) -> Vec<Mapping> {
+        let mut maps = Vec::new();
+        unsafe { collect_mappings(self.root, top_level(), 0, kernel, &mut maps) };
+        maps
+    }
+
+    /// Print every user mapping in this address space, along with the physical address each range starts at
+    pub fn dump(&self) {
+        for mapping in self.mappings(false) {
+            let paddr = self.translate(Vaddr(mapping.start)).unwrap();
+            println!("  {mapping} -> {paddr:?}");
+        }
+    }
+
+    /// Find the leaf entry that maps `vaddr`
+    fn lookup(&self, vaddr: Vaddr) -> Option<(PTE, PageSize)> {
+        unsafe { find_leaf(self.root, vaddr).map(|(pte, size)| (*pte, size)) }
+    }
+
+    /// Switch the hart to this address space.
+    /// Thanks to ASIDs the TLB entries of other address spaces survive the switch,
+    /// so the TLB only needs flushing when ASIDs run out or aren't supported.
+    pub fn activate(&mut self) {
+        let (asid, flush) = asid::assign(&mut self.asid);
+        // The address of the root table in pages, along with the paging mode
+        let satp = asid::satp(
+            paging_mode().satp_mode(),
+            asid,
+            self.root as usize / PAGE_SIZE,
+        );
+        unsafe {
+            core::arch::asm!("csrw satp, {satp}", satp = in(reg) satp);
+            if flush {
+                core::arch::asm!("sfence.vma");
+            }
+        }
+    }
+}
+
+/// Check `len` bytes from `start` is a page-aligned range inside the mmap range, and return its bounds
+fn mmap_range(start: Vaddr, len: u64) -> Option<(u64, u64)> {
+    let end = start.as_number().checked_add(len)?;
+    let aligned = start.is_aligned() && len % PAGE_SIZE as u64 == 0;
+    (aligned && len != 0 && USER_MMAP_BASE <= start.as_number() && end <= USER_MMAP_END)
+        .then_some((start.as_number(), end))
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        unsafe { free_address_space(self.root) };
+    }
+}
+
+/// Flush any cached translation for `vaddr` in the address space tagged with `asid`,
+/// or in every address space if there's no ASID to go on
+fn flush_tlb(vaddr: Vaddr, asid: Option<u16>) {
+    unsafe {
+        match asid {
+            Some(asid) => core::arch::asm!(
+                "sfence.vma {}, {}",
+                in(reg) vaddr.as_number(),
+                in(reg) asid as u64
+            ),
+            None => core::arch::asm!("sfence.vma {}, zero", in(reg) vaddr.as_number()),
+        }
+    }
+}
+
+/// Flush every cached translation in the address space tagged with `asid`,
+/// or in every address space if there's no ASID to go on
+fn flush_tlb_all(asid: Option<u16>) {
+    unsafe {
+        match asid {
+            // Global kernel entries are left alone
+            Some(asid) => core::arch::asm!("sfence.vma zero, {}", in(reg) asid as u64),
+            None => core::arch::asm!("sfence.vma"),
+        }
+    }
 }
 
 pub const fn align_up(value: usize, align: usize) -> usize {
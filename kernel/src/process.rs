@@ -1,20 +1,27 @@
 use crate::{
-    constants::{self, USER_BASE},
-    memory::{self, PAGE_SIZE, PTE, Paddr, PageFlags, Vaddr, alloc_pages},
-    println,
-    virtio::VIRTIO_BLK_PADDR,
+    constants::{
+        USER_HEAP_END, USER_HEAP_RANDOM, USER_IMAGE_RANDOM, USER_MMAP_END, USER_STACK_RANDOM,
+        USER_STACK_TOP,
+    },
+    elf::{Elf, ElfError, Segment},
+    fdt,
+    memory::{
+        AddressSpace, KernelStack, OutOfMemory, PAGE_SIZE, Paddr, PageFlags, Vaddr, free_pages,
+        try_alloc_pages,
+    },
+    println, random,
+    trap::{TrapFrame, return_to_user},
+    vma::{Access, FaultError, Vma, VmaKind},
     write_csr,
 };
-use core::{
-    arch::{asm, naked_asm},
-    mem::transmute,
-    ptr,
-};
+use alloc::vec::Vec;
+use common::ProcMemInfo;
+use core::{arch::naked_asm, ptr};
 
-/// Process stack size
-const PROC_STACK_SIZE: usize = 8192;
 /// Size of our process table
-const PROCS_MAX: usize = 8;
+pub const PROCS_MAX: usize = 8;
+/// Registers `switch_context` saves on the stack (ra + s0-s11 + one extra for alignment)
+const CONTEXT_REGISTERS: usize = 14;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ProcessState {
@@ -23,28 +30,64 @@ pub enum ProcessState {
     Exited,
 }
 
+/// Why a process couldn't be created
+#[derive(Debug, Clone)]
+pub enum ProcessError {
+    /// The executable can't be loaded
+    Elf(ElfError),
+    /// Every slot in the process table is taken
+    TableFull,
+    /// Memory ran out while setting it up
+    OutOfMemory,
+}
+
+impl From<ElfError> for ProcessError {
+    fn from(err: ElfError) -> Self {
+        ProcessError::Elf(err)
+    }
+}
+
+impl From<OutOfMemory> for ProcessError {
+    fn from(_: OutOfMemory) -> Self {
+        ProcessError::OutOfMemory
+    }
+}
+
+impl core::fmt::Display for ProcessError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            ProcessError::Elf(err) => write!(f, "{err}"),
+            ProcessError::TableFull => write!(f, "Process table is full"),
+            ProcessError::OutOfMemory => write!(f, "Out of memory"),
+        }
+    }
+}
+
+impl core::error::Error for ProcessError {}
+
 /// A process in the system
 #[derive(Debug)]
 pub struct Process {
     pid: Pid,
     sp: u64,
     state: ProcessState,
-    page_table: *mut PTE,
-    stack: [u8; PROC_STACK_SIZE],
+    address_space: Option<AddressSpace>,
+    /// The stack the kernel runs on while handling this process's traps
+    kernel_stack: Option<KernelStack>,
 }
 
 impl Process {
     /// Produce an uninitialized process structure
     /// _none_ of the fields in here are valid after this call
-    /// Especially `page_table`
+    /// Especially `address_space`
     /// It is the responsibility of the caller to initialize these fields
     pub const fn uninitialized() -> Self {
         Self {
             pid: Pid::idle(),
             state: ProcessState::Invalid,
             sp: 0,
-            page_table: 0 as *mut PTE,
-            stack: [0; PROC_STACK_SIZE],
+            address_space: None,
+            kernel_stack: None,
         }
     }
 
@@ -71,6 +114,35 @@ impl Process {
         println!("Process {} exiting", self.pid());
         self.state = ProcessState::Exited;
     }
+
+    /// The address space this process runs in
+    pub fn address_space(&mut self) -> &mut AddressSpace {
+        self.address_space
+            .as_mut()
+            .expect("Process without an address space")
+    }
+
+    /// Resolve a page fault this process took at `vaddr`.
+    /// Must be called without any locks held, as other processes may be killed to make room.
+    pub fn handle_page_fault(&mut self, vaddr: Vaddr, access: Access) -> Result<(), FaultError> {
+        retry_out_of_memory(|| self.address_space().handle_fault(vaddr, access))
+    }
+
+    /// Make sure the page at `vaddr` is there and allows `access`, as `AddressSpace::fault_in`.
+    /// Must be called without any locks held, as other processes may be killed to make room.
+    pub fn fault_in(&mut self, vaddr: Vaddr, access: Access) -> Result<(), FaultError> {
+        retry_out_of_memory(|| self.address_space().fault_in(vaddr, access))
+    }
+
+    /// Return the memory of an exited process to the system and free up its slot.
+    /// Must not be called on the running process, as its page table is still active.
+    fn reap(&mut self) {
+        assert!(self.state == ProcessState::Exited, "Reaping a live process");
+        // Dropping the address space frees its page tables and user pages
+        self.address_space = None;
+        self.kernel_stack = None;
+        self.state = ProcessState::Invalid;
+    }
 }
 
 impl core::fmt::Display for Process {
@@ -98,8 +170,9 @@ impl Scheduler {
             pid: Pid::idle(),
             sp: 0,
             state: ProcessState::Runnable,
-            page_table: 0 as *mut PTE,
-            stack: [0; PROC_STACK_SIZE],
+            address_space: None,
+            // The idle process runs on the boot stack
+            kernel_stack: None,
         };
         Self {
             procs: [
@@ -127,33 +200,81 @@ impl Scheduler {
         panic!("No runnable processes!?");
     }
 
+    /// Reap every exited process other than the one currently running.
+    /// Returns whether there were any.
+    fn reap_exited(&mut self) -> bool {
+        let current = self.current;
+        let mut reaped = false;
+        for proc in self.procs.iter_mut() {
+            if proc.state == ProcessState::Exited && proc.pid() != current {
+                proc.reap();
+                reaped = true;
+            }
+        }
+        reaped
+    }
+
+    /// Free up memory when it has run out: reap exited processes if there are any, or kill the
+    /// process with the most memory resident otherwise.
+    /// The running process is never picked, as it's the one that needs the memory. If it's all
+    /// that's left, its page fault fails and it's killed instead.
+    /// Returns false if there was nothing to free.
+    fn oom_kill(&mut self) -> bool {
+        if self.reap_exited() {
+            return true;
+        }
+        let current = self.current;
+        let victim = self
+            .procs
+            .iter_mut()
+            .filter(|proc| proc.is_runnable() && proc.pid() != current)
+            .filter_map(|proc| {
+                let resident = proc.address_space.as_ref()?.mem_usage().resident;
+                Some((proc, resident))
+            })
+            .filter(|(_, resident)| *resident != 0)
+            .max_by_key(|(_, resident)| *resident);
+        let Some((proc, resident)) = victim else {
+            return false;
+        };
+        println!(
+            "Out of memory: killing process {} ({} KiB resident)",
+            proc.pid(),
+            resident / 1024
+        );
+        proc.exit();
+        proc.reap();
+        true
+    }
+
     /// Cooperative yield.
     /// Switch between running process
     fn do_yield(&mut self) {
-        let next = self.find_next_process();
+        // A process that exited has switched away for good by the time anyone else yields
+        self.reap_exited();
+        let next_pid = self.find_next_process().pid();
         // If we decide to switch to the same process, we're done
-        if self.current != next.pid() {
+        if self.current != next_pid {
             // We've gotta switch running processes
+            let next = self.get_mut(next_pid);
             // Step 1:
             // Switch to the new process's page table
-            // Create the constant that goes in the SATP CSR
-            // Composed of:
-            // The address of the page table divided by the page size
-            // A flag that tells the processor we're using SV39 paging
-            let satp: usize = (8 << 60) | (next.page_table as usize / PAGE_SIZE);
-            // Write the value into the register, using memory fences
-            unsafe { asm!("sfence.vma", "csrw satp, {satp}", "sfence.vma", satp = in(reg) satp) };
+            next.address_space
+                .as_mut()
+                .expect("Switching to a process without an address space")
+                .activate();
 
             // Step 2: Save a trusted pointer to the kernel stack
             // Store the pointer to the bottom of the next stack in sscratch
-            unsafe {
-                let stack = next.stack.as_ptr().add(PROC_STACK_SIZE);
-                write_csr!("sscratch", stack as u64);
-            }
+            let stack = next
+                .kernel_stack
+                .as_ref()
+                .expect("Switching to a process without a kernel stack");
+            write_csr!("sscratch", stack.top());
 
             // Step 3: Swap the active process in the scheduler
             let prev = self.current;
-            self.current = next.pid();
+            self.current = next_pid;
             // Step 4: execute the context switch
             unsafe {
                 switch_context(
@@ -181,95 +302,221 @@ impl Scheduler {
 
     /// Finds the next free process in the process table and initializes it's PID
     /// _all other_ fields are uninitialized!
-    /// PANICS: if there are no new process slots.
-    unsafe fn find_free_process(&mut self) -> &mut Process {
+    /// Returns None if there are no new process slots.
+    unsafe fn find_free_process(&mut self) -> Option<&mut Process> {
         for (i, proc) in self.procs.iter_mut().enumerate() {
             if proc.state == ProcessState::Invalid {
                 (*proc).pid = Pid::new(i);
-                return proc;
+                return Some(proc);
             }
         }
-        panic!("No free processes");
-    }
-
-    /// Creates a new process that will execute the code at `pc`
-    pub fn create_process(&mut self, image: &[u8]) -> Pid {
-        // We are about to initialize proc
-        let proc = unsafe { self.find_free_process() };
-        // Allocate a page that will hold the process's page table
-        (*proc).page_table = alloc_pages(1) as *mut PTE;
-
-        // Map kernel memory
-
-        let start = &raw mut constants::__kernel_start as usize;
-        let end = &raw mut constants::__heap_end as usize;
-
-        for addr in (start..end).step_by(PAGE_SIZE) {
-            // while addr < &raw mut constants::__kernel_start {
-            let vaddr = Vaddr(addr as u64);
-            // The kernel lives in low memory, and each page points to the numerically same frame
-            memory::map_page(
-                proc.page_table,
-                vaddr,
-                Paddr(addr as *mut u8),
-                PageFlags::kernel_all(),
-            );
-        }
+        None
+    }
 
-        // Map virtio page
-        memory::map_page(
-            proc.page_table,
-            Vaddr(VIRTIO_BLK_PADDR),
-            Paddr(VIRTIO_BLK_PADDR as *mut u8),
-            PageFlags::default().read().write(),
+    /// Creates a new process that will execute the ELF executable `image`,
+    /// with a stack of `stack_size` bytes.
+    /// The image (if it's position-independent), heap and stack are each placed at random.
+    pub fn create_process(&mut self, image: &[u8], stack_size: usize) -> Result<Pid, ProcessError> {
+        let elf = Elf::parse(image)?;
+        let stack_size = stack_size.next_multiple_of(PAGE_SIZE) as u64;
+        // Leave room for the guard page between the mmap range and the lowest the stack can go
+        assert!(
+            stack_size != 0 && stack_size < USER_STACK_TOP - USER_STACK_RANDOM - USER_MMAP_END,
+            "Bad user stack size {stack_size:#x}"
         );
-
-        // Map user pages
-        let image_size = image.len();
-
-        for offset in (0..image_size).step_by(PAGE_SIZE) {
-            let page = alloc_pages(1);
-            let remaining = image_size - offset;
-            let copy_size = PAGE_SIZE.min(remaining);
-            unsafe {
-                ptr::copy_nonoverlapping(image[offset..].as_ptr(), page, copy_size);
-            }
-            memory::map_page(
-                proc.page_table,
-                Vaddr((USER_BASE + offset) as u64),
-                Paddr(page),
-                PageFlags::all(),
-            );
+        // How far the image is loaded from where it was linked to run
+        let bias = if elf.is_pie() {
+            random_offset(USER_IMAGE_RANDOM)
+        } else {
+            0
+        };
+        let stack_top = USER_STACK_TOP - random_offset(USER_STACK_RANDOM);
+        // We are about to initialize proc. It stays free until it's marked runnable at the end,
+        // so bailing out early leaves nothing to clean up.
+        let proc = unsafe { self.find_free_process() }.ok_or(ProcessError::TableFull)?;
+        // Create the address space the process will run in, which starts out with the kernel mapped
+        let mut address_space = AddressSpace::new()?;
+
+        // Map user pages, each segment with its own permissions
+        let mut image_end = 0;
+        for segment in elf.segments() {
+            image_end = image_end.max(load_segment(&mut address_space, &segment, bias)?);
         }
+        relocate(&mut address_space, &elf, bias)?;
+        // The heap starts out empty a little way past the image
+        address_space.init_heap(Vaddr(image_end + random_offset(USER_HEAP_RANDOM)));
+        // The stack is filled in as it's used. Nothing is ever mapped in the page below it,
+        // so overflowing it faults
+        address_space.add_vma(Vma::new(
+            stack_top - stack_size,
+            stack_top,
+            PageFlags::default().read().write().user(),
+            VmaKind::Stack,
+        ));
+
+        let stack = KernelStack::new(proc.pid.as_usize())?;
+        proc.address_space = Some(address_space);
 
         // Initialize the sp to look like switch_context had saved registers
-        let base = proc.stack.as_mut_ptr();
-        let stack_top = unsafe { base.add(PROC_STACK_SIZE) };
-
         unsafe {
-            // Allocate space for 14 saved registers (ra + s0-s11 + one extra for alignment)
-            const NUM_REGISTERS: usize = 14;
-            let sp: *mut u64 = transmute(stack_top);
-            let sp = sp.sub(NUM_REGISTERS);
+            // Allocate space for the saved registers
+            let sp = (stack.top() as *mut u64).sub(CONTEXT_REGISTERS);
 
             // Set up the saved register area
             *sp.add(0) = user_entry as u64; // ra = entry point
-            // s0-s11 are initialized to 0 (stack is already zeroed)
+            *sp.add(1) = stack_top; // s0 = user sp
+            *sp.add(2) = elf.entry() + bias; // s1 = user pc
+            // s2-s11 are initialized to 0 (stack is already zeroed)
 
             // Store the sp pointing to the saved register area
             proc.sp = sp as u64;
         }
+        proc.kernel_stack = Some(stack);
         // Finally, mark the process as runnable
         (*proc).state = ProcessState::Runnable;
-        return proc.pid;
+        Ok(proc.pid)
     }
+
+    /// Create a copy of the running process, which shares its memory copy-on-write.
+    /// The child returns from the trap `frame` at `user_pc`, with 0 in a0.
+    /// Returns None if the process table is full or memory runs out.
+    pub fn fork(&mut self, frame: &TrapFrame, user_pc: u64) -> Option<Pid> {
+        if !self
+            .procs
+            .iter()
+            .any(|proc| proc.state == ProcessState::Invalid)
+        {
+            return None;
+        }
+        let current = self.current;
+        let address_space = self.get_mut(current).address_space().fork().ok()?;
+        // There's a free slot, as checked above
+        let child = unsafe { self.find_free_process() }.unwrap();
+        let stack = KernelStack::new(child.pid.as_usize()).ok()?;
+        child.address_space = Some(address_space);
+
+        unsafe {
+            // Put a copy of the trap frame at the top of the child's kernel stack, where
+            // trap_vector would have left it
+            let child_frame = (stack.top() as *mut TrapFrame).sub(1);
+            ptr::copy_nonoverlapping(frame, child_frame, 1);
+            (*child_frame).set_result(0);
+
+            // Below that, registers for switch_context to restore that lead to return_to_user
+            let sp = (child_frame as *mut u64).sub(CONTEXT_REGISTERS);
+            ptr::write_bytes(sp, 0, CONTEXT_REGISTERS);
+            *sp.add(0) = return_to_user as *const () as u64; // ra
+            *sp.add(1) = user_pc; // s0
+            child.sp = sp as u64;
+        }
+        child.kernel_stack = Some(stack);
+        child.state = ProcessState::Runnable;
+        Some(child.pid)
+    }
+}
+
+/// A random page-aligned offset below `range`, or 0 if the kernel was booted with `norandmaps`
+/// to keep the layout of every process the same from one run to the next
+fn random_offset(range: u64) -> u64 {
+    if fdt::boot_info().has_arg("norandmaps") {
+        return 0;
+    }
+    random::below(range / PAGE_SIZE as u64) * PAGE_SIZE as u64
+}
+
+/// Copy `segment` into fresh pages of `address_space`, `bias` bytes above where it was linked,
+/// mapped with the permissions it asks for.
+/// Returns the end of the segment in memory, rounded up to a page.
+/// Segments that allow no access at all, break W^X, overlap another segment or don't fit between
+/// the first page and the heap limit are rejected.
+fn load_segment(
+    address_space: &mut AddressSpace,
+    segment: &Segment,
+    bias: u64,
+) -> Result<u64, ProcessError> {
+    let bad = ProcessError::Elf(ElfError::BadSegment(segment.vaddr));
+    // An entry without any of R, W and X would be taken for a pointer to the next level
+    if !(segment.readable() || segment.writable() || segment.executable()) {
+        return Err(bad);
+    }
+    let mut flags = PageFlags::default().user();
+    // Write-only entries are reserved, so writable pages must be readable too
+    if segment.readable() || segment.writable() {
+        flags = flags.read();
+    }
+    if segment.writable() {
+        flags = flags.write();
+    }
+    if segment.executable() {
+        flags = flags.execute();
+    }
+    let Some(vaddr) = segment.vaddr.checked_add(bias) else {
+        return Err(bad);
+    };
+    let Some(end) = vaddr
+        .checked_add(segment.mem_size)
+        .and_then(|end| end.checked_next_multiple_of(PAGE_SIZE as u64))
+    else {
+        return Err(bad);
+    };
+    let start = vaddr & !(PAGE_SIZE as u64 - 1);
+    // The first page stays unmapped, so null pointers fault
+    if start < PAGE_SIZE as u64
+        || end <= start
+        || end > USER_HEAP_END
+        || segment.data.len() as u64 > segment.mem_size
+        || flags.violates_wx()
+        || address_space.overlaps(start, end)
+    {
+        return Err(bad);
+    }
+    address_space.add_vma(Vma::new(start, end, flags, VmaKind::Image));
+
+    for page in (start..end).step_by(PAGE_SIZE) {
+        let frame = try_alloc_pages(1)?;
+        // The part of the segment's file contents that falls into this page, the rest stays zero
+        let from = page.max(vaddr);
+        let to = (page + PAGE_SIZE as u64).min(vaddr + segment.data.len() as u64);
+        if from < to {
+            let offset = (from - vaddr) as usize;
+            unsafe {
+                ptr::copy_nonoverlapping(
+                    segment.data[offset..].as_ptr(),
+                    frame.add((from - page) as usize),
+                    (to - from) as usize,
+                );
+            }
+        }
+        if let Err(err) = address_space.map(Vaddr(page), Paddr(frame), flags) {
+            free_pages(frame, 1);
+            return Err(err.into());
+        }
+    }
+    Ok(end)
+}
+
+/// Fix up the addresses in a position-independent image that was loaded `bias` bytes above where
+/// it was linked, by writing straight into the freshly loaded pages
+fn relocate(address_space: &mut AddressSpace, elf: &Elf, bias: u64) -> Result<(), ElfError> {
+    for relocation in elf.relocations(bias) {
+        let (place, value) = relocation?;
+        let in_image = address_space
+            .find_vma(Vaddr(place))
+            .is_some_and(|vma| matches!(vma.kind, VmaKind::Image));
+        // Aligned, so the value can't straddle two pages
+        let paddr = address_space
+            .translate(Vaddr(place))
+            .filter(|_| in_image && place % 8 == 0)
+            .ok_or(ElfError::BadRelocation(place))?;
+        unsafe { (paddr.0 as *mut u64).write(value) };
+    }
+    Ok(())
 }
 
 /// Executes a context switch,
 /// saving callee save registers on the stack
 #[unsafe(naked)]
 unsafe extern "C" fn switch_context(prev_sp: *const u64, next_sp: *const u64) {
-    const NUM_REGS: usize = 14;
     naked_asm!(
         "addi sp, sp, -{num_regs} * {size}", // Allocate space on stack
         // Save callee-save registers
@@ -307,7 +554,7 @@ unsafe extern "C" fn switch_context(prev_sp: *const u64, next_sp: *const u64) {
         "addi sp, sp, {num_regs} * {size}",
         "ret",
         "ebreak",
-        num_regs = const NUM_REGS,
+        num_regs = const CONTEXT_REGISTERS,
         size = const 8);
 }
 
@@ -363,8 +610,32 @@ pub fn do_yield() {
 }
 
 /// Global function to create a new process
-pub fn create_process(image: &[u8]) -> Pid {
-    unsafe { (*core::ptr::addr_of_mut!(GLOBAL_SCHEDULER)).create_process(image) }
+pub fn create_process(image: &[u8], stack_size: usize) -> Result<Pid, ProcessError> {
+    unsafe { (*core::ptr::addr_of_mut!(GLOBAL_SCHEDULER)).create_process(image, stack_size) }
+}
+
+/// Global function to fork the running process
+pub fn fork(frame: &TrapFrame, user_pc: u64) -> Option<Pid> {
+    unsafe { (*core::ptr::addr_of_mut!(GLOBAL_SCHEDULER)).fork(frame, user_pc) }
+}
+
+/// Global function to free up memory once it has run out, by killing a process if need be.
+/// Returns false if there was nothing to free.
+fn oom_kill() -> bool {
+    unsafe { (*core::ptr::addr_of_mut!(GLOBAL_SCHEDULER)).oom_kill() }
+}
+
+/// Run `fault` until it gets through, killing another process each time it runs out of memory,
+/// for as long as there's one left to kill
+fn retry_out_of_memory(
+    mut fault: impl FnMut() -> Result<(), FaultError>,
+) -> Result<(), FaultError> {
+    loop {
+        match fault() {
+            Err(FaultError::OutOfMemory(_)) if oom_kill() => continue,
+            result => return result,
+        }
+    }
 }
 
 /// Global function to list the current process table
@@ -375,6 +646,35 @@ pub fn ps() {
     }
 }
 
+/// The address space of the live process in slot `index` of the process table, if there is one
+pub fn address_space_of(index: usize) -> Option<&'static mut AddressSpace> {
+    unsafe {
+        let ptr = core::ptr::addr_of_mut!(GLOBAL_SCHEDULER);
+        let proc = (*ptr).procs.get_mut(index)?;
+        if proc.state != ProcessState::Runnable {
+            return None;
+        }
+        proc.address_space.as_mut()
+    }
+}
+
+/// Memory usage of every live process
+pub fn mem_usage() -> Vec<ProcMemInfo> {
+    unsafe {
+        let ptr = core::ptr::addr_of!(GLOBAL_SCHEDULER);
+        (*ptr)
+            .running_processes()
+            .filter_map(|proc| {
+                let usage = proc.address_space.as_ref()?.mem_usage();
+                Some(ProcMemInfo {
+                    pid: proc.pid.as_usize() as u64,
+                    ..usage
+                })
+            })
+            .collect()
+    }
+}
+
 /// Get a mutable reference to the currently running process
 pub fn current_process() -> &'static mut Process {
     unsafe {
@@ -384,17 +684,20 @@ pub fn current_process() -> &'static mut Process {
     }
 }
 
-const STATUS_PIE: u64 = 1 << 5;
+pub const STATUS_PIE: u64 = 1 << 5;
 
+/// Start a new process in user mode, with its stack pointer in s0 and entry point in s1.
+/// The kernel stack is left behind, sscratch already points at it.
 #[unsafe(naked)]
 extern "C" fn user_entry() {
     naked_asm!(
-        "li t0, {sepc}",
-        "csrw sepc, t0",
+        "csrw sepc, s1",
         "li t1, {sstatus}",
         "csrw sstatus, t1",
+        "mv sp, s0",
+        "li s0, 0",
+        "li s1, 0",
         "sret",
-        sepc = const USER_BASE,
         sstatus = const STATUS_PIE,
     )
 }
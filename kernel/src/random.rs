@@ -0,0 +1,44 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::read_csr;
+
+/// Added to the state for every number drawn, from SplitMix64
+const GAMMA: u64 = 0x9E37_79B9_7F4A_7C15;
+
+/// State of the generator, seeded by `init`
+static STATE: AtomicU64 = AtomicU64::new(0);
+
+/// Scramble the bits of `x`, so that nearby inputs give unrelated outputs
+fn mix(mut x: u64) -> u64 {
+    x = (x ^ (x >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    x ^ (x >> 31)
+}
+
+/// Seed the generator from timer jitter. How long a bit of busy work takes varies with whatever
+/// else the machine (or the host, under an emulator) is up to, and the low bits of every timing
+/// are folded into the seed.
+pub fn init() {
+    let mut seed = read_csr!("time");
+    for round in 0..64 {
+        let start = read_csr!("time");
+        let mut work = round;
+        for _ in 0..256 {
+            work = core::hint::black_box(mix(work));
+        }
+        seed = mix(seed ^ (read_csr!("time") - start) ^ work);
+    }
+    STATE.store(seed, Ordering::Relaxed);
+}
+
+/// A random number. The time is mixed into each one, so numbers drawn at different moments
+/// differ even if the seed was guessed.
+pub fn next() -> u64 {
+    mix(STATE.fetch_add(GAMMA, Ordering::Relaxed) ^ read_csr!("time"))
+}
+
+/// A random number below `bound`, which must not be 0.
+/// Slightly biased towards small numbers unless `bound` is a power of two.
+pub fn below(bound: u64) -> u64 {
+    next() % bound
+}
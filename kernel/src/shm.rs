@@ -0,0 +1,117 @@
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use crate::frame::{self, FRAME_ALLOCATOR};
+use crate::memory::{AddressSpace, PAGE_SIZE, Paddr, PageFlags, Vaddr, try_alloc_pages};
+use crate::vma::VmaKind;
+
+/// Identifies a shared memory object, the same in every process
+pub type ShmId = u64;
+
+/// A named block of memory that any number of processes can map at once.
+/// The object holds a reference to each of its frames and so does every mapping,
+/// so the memory lives on until the object is destroyed and the last mapping is gone.
+struct SharedMemory {
+    name: String,
+    frames: Vec<Paddr>,
+}
+
+// The frames are plain physical memory, owned by the object
+unsafe impl Send for SharedMemory {}
+
+struct Registry {
+    objects: BTreeMap<ShmId, SharedMemory>,
+    next_id: ShmId,
+}
+
+static SHARED_MEMORY: spin::Mutex<Registry> = spin::Mutex::new(Registry {
+    objects: BTreeMap::new(),
+    next_id: 1,
+});
+
+/// Find the object called `name`, or create it with `len` bytes of zeroed memory if there's none.
+/// Returns None if the object exists but is smaller than `len`, or there's no memory for it.
+pub fn open(name: &str, len: usize) -> Option<ShmId> {
+    let mut registry = SHARED_MEMORY.lock();
+    if let Some((&id, object)) = registry.objects.iter().find(|(_, obj)| obj.name == name) {
+        return (object.frames.len() * PAGE_SIZE >= len).then_some(id);
+    }
+    if len == 0 {
+        return None;
+    }
+    let pages = len.div_ceil(PAGE_SIZE);
+    // Memory could never be found for more pages than there are
+    if pages > FRAME_ALLOCATOR.total_count() {
+        return None;
+    }
+    let mut frames = Vec::new();
+    frames.try_reserve_exact(pages).ok()?;
+    for _ in 0..pages {
+        match try_alloc_pages(1) {
+            Ok(page) => frames.push(Paddr(page)),
+            Err(_) => {
+                for frame in frames {
+                    frame::free_frames(frame, 1);
+                }
+                return None;
+            }
+        }
+    }
+    let id = registry.next_id;
+    registry.next_id += 1;
+    registry.objects.insert(
+        id,
+        SharedMemory {
+            name: name.into(),
+            frames,
+        },
+    );
+    Some(id)
+}
+
+/// Map all of object `id` into `space` with `flags`, and return where it ended up.
+/// Returns None if there's no such object, if `flags` allow no access at all,
+/// or if there's no room or memory for the mapping.
+pub fn map(id: ShmId, space: &mut AddressSpace, flags: PageFlags) -> Option<Vaddr> {
+    let registry = SHARED_MEMORY.lock();
+    let object = registry.objects.get(&id)?;
+    let len = (object.frames.len() * PAGE_SIZE) as u64;
+    let start = space.mmap(None, len, flags, VmaKind::Shared)?;
+    for (i, &frame) in object.frames.iter().enumerate() {
+        // The mapping's reference is dropped again when the page is unmapped
+        FRAME_ALLOCATOR.share(frame, 1);
+        if space
+            .map(start + Vaddr((i * PAGE_SIZE) as u64), frame, flags)
+            .is_err()
+        {
+            frame::free_frames(frame, 1);
+            // Unmapping the area drops the references of the pages that did get mapped
+            space.munmap(start, len);
+            return None;
+        }
+    }
+    Some(start)
+}
+
+/// Unmap the shared memory mapped at `start` from `space`
+pub fn unmap(space: &mut AddressSpace, start: Vaddr) -> bool {
+    match space.find_vma(start).copied() {
+        Some(vma) if matches!(vma.kind, VmaKind::Shared) && vma.start == start.as_number() => {
+            space.munmap(start, vma.end - vma.start)
+        }
+        _ => false,
+    }
+}
+
+/// Remove object `id`, so it can't be mapped again.
+/// Existing mappings are unaffected, and the memory is freed along with the last of them.
+pub fn destroy(id: ShmId) -> bool {
+    let Some(object) = SHARED_MEMORY.lock().objects.remove(&id) else {
+        return false;
+    };
+    for frame in object.frames {
+        frame::free_frames(frame, 1);
+    }
+    true
+}
@@ -0,0 +1,171 @@
+use core::marker::PhantomData;
+
+use crate::frame;
+use crate::memory::{PAGE_SIZE, align_up};
+
+struct Slabs {
+    /// Address of the first free object, or 0 if the free list is empty.
+    /// Each free object stores the address of the next one in its first word.
+    free: usize,
+    slabs: usize,
+    in_use: usize,
+    allocs: usize,
+    frees: usize,
+}
+
+/// Usage statistics for a single slab cache
+#[derive(Debug, Clone, Copy)]
+pub struct SlabStats {
+    pub name: &'static str,
+    pub object_size: usize,
+    pub objects_per_slab: usize,
+    pub slabs: usize,
+    pub in_use: usize,
+    pub allocs: usize,
+    pub frees: usize,
+}
+
+impl core::fmt::Display for SlabStats {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "slab {}: {} / {} objects of {} bytes in use across {} slabs ({} allocs, {} frees)",
+            self.name,
+            self.in_use,
+            self.slabs * self.objects_per_slab,
+            self.object_size,
+            self.slabs,
+            self.allocs,
+            self.frees
+        )
+    }
+}
+
+/// A cache of fixed-size `T` objects.
+/// Slabs of contiguous frames are taken from the frame allocator and carved into objects,
+/// which are handed out and returned through an intrusive free list in O(1).
+/// Slabs are kept by the cache once allocated, so freed objects are reused
+/// and stay close together in memory.
+pub struct SlabCache<T> {
+    name: &'static str,
+    slabs: spin::Mutex<Slabs>,
+    _marker: PhantomData<T>,
+}
+
+impl<T> SlabCache<T> {
+    /// Size of each object slot, which must also be able to hold the free-list link
+    const OBJECT_SIZE: usize = align_up(
+        if size_of::<T>() > size_of::<usize>() {
+            size_of::<T>()
+        } else {
+            size_of::<usize>()
+        },
+        Self::OBJECT_ALIGN,
+    );
+    const OBJECT_ALIGN: usize = if align_of::<T>() > align_of::<usize>() {
+        align_of::<T>()
+    } else {
+        align_of::<usize>()
+    };
+    const SLAB_PAGES: usize = align_up(Self::OBJECT_SIZE, PAGE_SIZE) / PAGE_SIZE;
+    const OBJECTS_PER_SLAB: usize = Self::SLAB_PAGES * PAGE_SIZE / Self::OBJECT_SIZE;
+
+    pub const fn new(name: &'static str) -> Self {
+        assert!(
+            Self::OBJECT_ALIGN <= PAGE_SIZE,
+            "Slab objects can't be aligned beyond a page"
+        );
+        Self {
+            name,
+            slabs: spin::Mutex::new(Slabs {
+                free: 0,
+                slabs: 0,
+                in_use: 0,
+                allocs: 0,
+                frees: 0,
+            }),
+            _marker: PhantomData,
+        }
+    }
+
+    /// Put all of the objects in the fresh slab at `base` on the free list
+    fn grow(slabs: &mut Slabs, base: usize) {
+        for i in (0..Self::OBJECTS_PER_SLAB).rev() {
+            let object = base + i * Self::OBJECT_SIZE;
+            unsafe { (object as *mut usize).write(slabs.free) };
+            slabs.free = object;
+        }
+        slabs.slabs += 1;
+    }
+
+    /// Get an uninitialized object slot, or None if there's no memory for another slab
+    fn try_alloc_slot(&self) -> Option<*mut T> {
+        let mut slabs = self.slabs.lock();
+        if slabs.free == 0 {
+            // Running out of frames can free objects back into this cache,
+            // so the lock can't be held while taking more
+            drop(slabs);
+            let base = frame::try_alloc_frames(Self::SLAB_PAGES)?.0 as usize;
+            slabs = self.slabs.lock();
+            Self::grow(&mut slabs, base);
+        }
+        let object = slabs.free;
+        slabs.free = unsafe { (object as *mut usize).read() };
+        slabs.in_use += 1;
+        slabs.allocs += 1;
+        Some(object as *mut T)
+    }
+
+    /// Get an uninitialized object slot
+    /// PANICS: if physical memory is exhausted
+    fn alloc_slot(&self) -> *mut T {
+        self.try_alloc_slot()
+            .unwrap_or_else(|| panic!("Out of memory growing slab cache {}", self.name))
+    }
+
+    /// Allocate an object from the cache and move `value` into it
+    pub fn alloc(&self, value: T) -> *mut T {
+        let object = self.alloc_slot();
+        unsafe { object.write(value) };
+        object
+    }
+
+    /// Allocate a zero-filled object from the cache.
+    /// SAFETY: all-zeroes must be a valid `T`
+    pub unsafe fn alloc_zeroed(&self) -> *mut T {
+        unsafe { self.try_alloc_zeroed() }
+            .unwrap_or_else(|| panic!("Out of memory growing slab cache {}", self.name))
+    }
+
+    /// Allocate a zero-filled object from the cache, or None if memory has run out.
+    /// SAFETY: all-zeroes must be a valid `T`
+    pub unsafe fn try_alloc_zeroed(&self) -> Option<*mut T> {
+        let object = self.try_alloc_slot()?;
+        unsafe { core::ptr::write_bytes(object as *mut u8, 0, size_of::<T>()) };
+        Some(object)
+    }
+
+    /// Drop an object and return its slot to the cache.
+    /// SAFETY: `object` must have come from this cache and must not be used afterwards
+    pub unsafe fn free(&self, object: *mut T) {
+        unsafe { core::ptr::drop_in_place(object) };
+        let mut slabs = self.slabs.lock();
+        unsafe { (object as *mut usize).write(slabs.free) };
+        slabs.free = object as usize;
+        slabs.in_use -= 1;
+        slabs.frees += 1;
+    }
+
+    pub fn stats(&self) -> SlabStats {
+        let slabs = self.slabs.lock();
+        SlabStats {
+            name: self.name,
+            object_size: Self::OBJECT_SIZE,
+            objects_per_slab: Self::OBJECTS_PER_SLAB,
+            slabs: slabs.slabs,
+            in_use: slabs.in_use,
+            allocs: slabs.allocs,
+            frees: slabs.frees,
+        }
+    }
+}
@@ -0,0 +1,156 @@
+use alloc::vec;
+use alloc::vec::Vec;
+
+use crate::constants::USER_END;
+use crate::memory::{PAGE_SIZE, Paddr, Vaddr};
+use crate::println;
+use crate::process::{self, PROCS_MAX};
+use crate::virtio::{BlockDeviceDriver, IOError, SECTOR_SIZE};
+
+/// A disk that user pages are written out to when physical memory runs low.
+/// The disk is divided into page-sized slots, each holding one swapped-out page.
+struct Swap {
+    device: BlockDeviceDriver,
+    /// References to each slot from swapped-out page table entries, 0 if the slot is free.
+    /// A page swapped out before a fork is referenced by both processes.
+    slots: Vec<u16>,
+    /// Where the clock hand points: the process table index and address it resumes from
+    hand: (usize, u64),
+}
+
+// The driver's queue is only ever touched with the lock held
+unsafe impl Send for Swap {}
+
+static SWAP: spin::Mutex<Option<Swap>> = spin::Mutex::new(None);
+
+impl Swap {
+    fn sector(slot: usize) -> u64 {
+        (slot * PAGE_SIZE) as u64 / SECTOR_SIZE
+    }
+
+    fn write(&mut self, slot: usize, frame: Paddr) -> Result<(), IOError> {
+        let page = unsafe { core::slice::from_raw_parts(frame.0, PAGE_SIZE) };
+        let first = Self::sector(slot);
+        for (sector, data) in (first..).zip(page.chunks(SECTOR_SIZE as usize)) {
+            self.device.disk_write(data, sector)?;
+        }
+        Ok(())
+    }
+
+    fn read(&mut self, slot: usize, frame: Paddr) -> Result<(), IOError> {
+        let page = unsafe { core::slice::from_raw_parts_mut(frame.0, PAGE_SIZE) };
+        let first = Self::sector(slot);
+        for (sector, data) in (first..).zip(page.chunks_mut(SECTOR_SIZE as usize)) {
+            self.device.disk_read(data, sector)?;
+        }
+        Ok(())
+    }
+
+    /// Go round the user pages of every process with the clock hand, starting where it last stopped,
+    /// until a page to swap out turns up. Returns the process table index and address of the page.
+    fn find_victim(&mut self, dirty: bool) -> Option<(usize, Vaddr)> {
+        let (first, resume) = self.hand;
+        // The first time round clears the accessed bits of everything the hand passes, so by the
+        // third time round (which covers the pages behind the hand twice) a page has turned up
+        // unless there's none that could be swapped out at all
+        for round in 0..3 {
+            for offset in 0..PROCS_MAX {
+                let index = (first + offset) % PROCS_MAX;
+                let Some(space) = process::address_space_of(index) else {
+                    continue;
+                };
+                let from = if round == 0 && offset == 0 { resume } else { 0 };
+                if let Some(vaddr) = space.find_victim(from, USER_END, dirty) {
+                    self.hand = (index, vaddr.as_number() + PAGE_SIZE as u64);
+                    return Some((index, vaddr));
+                }
+            }
+        }
+        None
+    }
+}
+
+/// Start swapping to the block device at `base`, if there is one
+pub fn init(base: u64) {
+    if !BlockDeviceDriver::is_present(base) {
+        println!("swap: No disk at {base:#x}, swapping disabled");
+        return;
+    }
+    let mut device = BlockDeviceDriver::new(base);
+    // Swapping happens when memory has run out, so it mustn't need any to do its I/O
+    device.reserve_request();
+    let slots = vec![0; device.capacity as usize / PAGE_SIZE];
+    println!("swap: {} pages of swap space", slots.len());
+    SWAP.lock().replace(Swap {
+        device,
+        slots,
+        hand: (0, 0),
+    });
+}
+
+/// Free up a frame by taking a user page out of memory. Pages that were filled in on demand and
+/// not written to since are simply dropped, anything else is written to swap first.
+/// Returns false if there's no page that can go, or no swap to put it in.
+pub fn reclaim() -> bool {
+    // Swapping a page out can need memory itself, and if that runs out there's nothing more to do
+    let Some(mut lock) = SWAP.try_lock() else {
+        return false;
+    };
+    let Some(swap) = lock.as_mut() else {
+        return false;
+    };
+    let free_slot = swap.slots.iter().position(|refs| *refs == 0);
+    let Some((index, vaddr)) = swap.find_victim(free_slot.is_some()) else {
+        return false;
+    };
+    let space = process::address_space_of(index).unwrap();
+    if space.is_clean(vaddr) {
+        space.swap_out(vaddr, None);
+        return true;
+    }
+    // Only clean pages are picked when there's no free slot
+    let slot = free_slot.unwrap();
+    if let Err(err) = swap.write(slot, space.translate(vaddr).unwrap()) {
+        println!("swap: Writing out {vaddr:?} failed: {err}");
+        return false;
+    }
+    swap.slots[slot] = 1;
+    space.swap_out(vaddr, Some(slot));
+    true
+}
+
+/// Number of page-sized slots of swap, and how many of them are in use
+pub fn usage() -> (usize, usize) {
+    SWAP.lock().as_ref().map_or((0, 0), |swap| {
+        let used = swap.slots.iter().filter(|refs| **refs != 0).count();
+        (swap.slots.len(), used)
+    })
+}
+
+/// Read the page in swap `slot` into `frame`
+pub fn read(slot: usize, frame: Paddr) -> Result<(), IOError> {
+    let mut lock = SWAP.lock();
+    let swap = lock.as_mut().expect("Swapped-out page without swap");
+    let result = swap.read(slot, frame);
+    if let Err(err) = &result {
+        println!("swap: Reading slot {slot} failed: {err}");
+    }
+    result
+}
+
+/// Take another reference to swap `slot`, for a copy of an entry pointing to it
+pub fn share(slot: usize) {
+    let mut lock = SWAP.lock();
+    let swap = lock.as_mut().expect("Swapped-out page without swap");
+    swap.slots[slot] = swap.slots[slot]
+        .checked_add(1)
+        .expect("Too many references to swap slot");
+}
+
+/// Drop a reference to swap `slot`, freeing it once nothing points to it
+pub fn release(slot: usize) {
+    let mut lock = SWAP.lock();
+    let swap = lock.as_mut().expect("Swapped-out page without swap");
+    assert!(swap.slots[slot] > 0, "Double free of swap slot {slot}");
+    swap.slots[slot] -= 1;
+}
@@ -94,6 +94,29 @@ impl<'driver, 'block_dev> FileSystem<'block_dev, 'driver> {
             .collect::<Vec<_>>();
         Ok(Self { dev, files })
     }
+
+    /// Find the file called `name`
+    pub fn lookup(&self, name: &str) -> Option<&FileRef<'block_dev>> {
+        self.files.iter().find(|file| file.header.name == name)
+    }
+}
+
+// The files never change once the archive is parsed, and the device is only used to read it
+unsafe impl Send for FileSystem<'static, 'static> {}
+unsafe impl Sync for FileSystem<'static, 'static> {}
+
+static FILESYSTEM: spin::Once<FileSystem<'static, 'static>> = spin::Once::new();
+
+/// Read the filesystem off `dev` and make it available through `filesystem`
+pub fn init_filesystem(dev: &'static BlockDevice<'static>) -> Result<(), FSError> {
+    let fs = FileSystem::init(dev)?;
+    FILESYSTEM.call_once(|| fs);
+    Ok(())
+}
+
+/// The filesystem read at boot
+pub fn filesystem() -> &'static FileSystem<'static, 'static> {
+    FILESYSTEM.get().expect("Filesystem not initialized")
 }
 
 #[derive(Debug)]
@@ -1,9 +1,13 @@
 use crate::{
+    fdt,
+    memory::{self, KernelStack, PAGE_SIZE, PageFlags, Vaddr},
     process::{self, do_yield},
     sbi::{self, putchar},
+    shm, tar, uaccess,
+    vma::{Access, FileBacking, VmaKind},
 };
-use alloc::{fmt::format, string::String};
-use common::Syscall;
+use alloc::{fmt::format, string::String, vec};
+use common::{MAP_ANONYMOUS, MAP_FIXED, PROT_EXEC, PROT_READ, PROT_WRITE, Syscall};
 use core::arch::naked_asm;
 
 #[macro_export]
@@ -28,7 +32,7 @@ macro_rules! write_csr {
 
 #[derive(Debug)]
 #[repr(packed)]
-struct TrapFrame {
+pub struct TrapFrame {
     x1: u64,
     x2: u64,
     x3: u64,
@@ -63,6 +67,13 @@ struct TrapFrame {
     sp: u64,
 }
 
+impl TrapFrame {
+    /// Set the value a syscall returns in a0
+    pub fn set_result(&mut self, value: u64) {
+        self.x10 = value;
+    }
+}
+
 #[unsafe(no_mangle)]
 #[unsafe(naked)]
 #[unsafe(link_section = ".text.stvec")]
@@ -102,11 +113,20 @@ pub unsafe extern "C" fn trap_vector() {
         "sd x30, 232(sp)",
         "sd x31, 240(sp)",
         "csrr a0, sscratch", // Retrive and save sp at time of exception
-        "sw a0, 248(sp)",
-        "addi a0, sp, 8 * 31",
+        "sd a0, 248(sp)",
+        "addi a0, sp, 256", // Point sscratch back at the top of the kernel stack
         "csrw sscratch, a0",
         "mv a0, sp", // Restore the stack before calling handler
         "call trap_handler",
+        "j {trap_return}",
+        trap_return = sym trap_return,
+    );
+}
+
+/// Restore the user registers from the trap frame at `sp` and return to user mode
+#[unsafe(naked)]
+unsafe extern "C" fn trap_return() {
+    naked_asm!(
         "ld x1, 0(sp)",
         // skip x2 (sp) - restore it last from offset 248
         "ld x3, 16(sp)",
@@ -145,6 +165,20 @@ pub unsafe extern "C" fn trap_vector() {
     );
 }
 
+/// Return to user mode through the trap frame at `sp`, resuming at the pc in `s0`.
+/// Forked processes start out here, halfway through their parent's syscall.
+#[unsafe(naked)]
+pub unsafe extern "C" fn return_to_user() {
+    naked_asm!(
+        "csrw sepc, s0",
+        "li t0, {sstatus}",
+        "csrw sstatus, t0",
+        "j {trap_return}",
+        sstatus = const process::STATUS_PIE,
+        trap_return = sym trap_return,
+    );
+}
+
 fn handle_syscall(
     _scause: u64,
     user_pc: u64,
@@ -154,7 +188,7 @@ fn handle_syscall(
     let sysno = unsafe { (*frame).x13 };
     let call = sysno.try_into();
     match call {
-        Ok(call) => execute_syscall(call, frame),
+        Ok(call) => execute_syscall(call, user_pc, frame),
         Err(other) => {
             let frame_ref = unsafe { frame.as_ref() }.unwrap();
             panic!("Unknown syscall: {other}, frame {:?}", frame_ref);
@@ -165,7 +199,7 @@ fn handle_syscall(
     Ok(())
 }
 
-fn execute_syscall(syscall: Syscall, frame: *mut TrapFrame) {
+fn execute_syscall(syscall: Syscall, user_pc: u64, frame: *mut TrapFrame) {
     match syscall {
         Syscall::PUTCHAR => {
             let frame_ref = unsafe { frame.as_ref() }.unwrap();
@@ -177,6 +211,66 @@ fn execute_syscall(syscall: Syscall, frame: *mut TrapFrame) {
                 (*frame).x10 = chr as u64;
             }
         }
+        Syscall::SBRK => {
+            let increment = unsafe { (*frame).x10 } as i64;
+            let old_brk = process::current_process().address_space().sbrk(increment);
+            unsafe {
+                // The old break on success, -1 if the heap couldn't be moved
+                (*frame).x10 = old_brk.unwrap_or(u64::MAX);
+            }
+        }
+        Syscall::MMAP => unsafe {
+            (*frame).x10 = sys_mmap(&*frame).unwrap_or(u64::MAX);
+        },
+        Syscall::MUNMAP => unsafe {
+            let (addr, len) = ((*frame).x10, (*frame).x11);
+            let unmapped = process::current_process()
+                .address_space()
+                .munmap(Vaddr(addr), len);
+            (*frame).x10 = if unmapped { 0 } else { u64::MAX };
+        },
+        Syscall::MPROTECT => unsafe {
+            let (addr, len, prot) = ((*frame).x10, (*frame).x11, (*frame).x12);
+            let changed = process::current_process().address_space().mprotect(
+                Vaddr(addr),
+                len,
+                prot_flags(prot),
+            );
+            (*frame).x10 = if changed { 0 } else { u64::MAX };
+        },
+        Syscall::SHMOPEN => unsafe {
+            let (name, name_len, len) = ((*frame).x10, (*frame).x11, (*frame).x12);
+            let id = read_user_str(name, name_len).and_then(|name| shm::open(&name, len as usize));
+            (*frame).x10 = id.unwrap_or(u64::MAX);
+        },
+        Syscall::SHMMAP => unsafe {
+            let (id, prot) = ((*frame).x10, (*frame).x11);
+            let space = process::current_process().address_space();
+            let start = shm::map(id, space, prot_flags(prot));
+            (*frame).x10 = start.map_or(u64::MAX, Vaddr::as_number);
+        },
+        Syscall::SHMUNMAP => unsafe {
+            let space = process::current_process().address_space();
+            let unmapped = shm::unmap(space, Vaddr((*frame).x10));
+            (*frame).x10 = if unmapped { 0 } else { u64::MAX };
+        },
+        Syscall::SHMDESTROY => unsafe {
+            let destroyed = shm::destroy((*frame).x10);
+            (*frame).x10 = if destroyed { 0 } else { u64::MAX };
+        },
+        Syscall::FORK => unsafe {
+            // The child returns from the same ecall, but with 0 as its result
+            let child = process::fork(&*frame, user_pc + 4);
+            (*frame).x10 = child.map_or(u64::MAX, |pid| pid.as_usize() as u64);
+        },
+        Syscall::VMMAP => unsafe {
+            let (buf, capacity) = ((*frame).x10, (*frame).x11);
+            (*frame).x10 = sys_vmmap(buf, capacity).unwrap_or(u64::MAX);
+        },
+        Syscall::MEMINFO => unsafe {
+            let (info, procs, capacity) = ((*frame).x10, (*frame).x11, (*frame).x12);
+            (*frame).x10 = sys_meminfo(info, procs, capacity).unwrap_or(u64::MAX);
+        },
         Syscall::EXIT => {
             process::current_process().exit();
             do_yield();
@@ -185,6 +279,124 @@ fn execute_syscall(syscall: Syscall, frame: *mut TrapFrame) {
     }
 }
 
+/// Longest path or name a syscall accepts
+const PATH_MAX: u64 = 256;
+
+/// Read a string of `len` bytes at `vaddr` out of user memory
+fn read_user_str(vaddr: u64, len: u64) -> Option<String> {
+    if len > PATH_MAX {
+        return None;
+    }
+    let mut bytes = vec![0; len as usize];
+    uaccess::copy_from_user(&mut bytes, vaddr).ok()?;
+    String::from_utf8(bytes).ok()
+}
+
+/// Turn `mmap` protection bits into flags for user pages.
+/// Writable pages must be readable too, as write-only entries are reserved.
+fn prot_flags(prot: u64) -> PageFlags {
+    let mut flags = PageFlags::default().user();
+    if prot & (PROT_READ | PROT_WRITE) != 0 {
+        flags = flags.read();
+    }
+    if prot & PROT_WRITE != 0 {
+        flags = flags.write();
+    }
+    if prot & PROT_EXEC != 0 {
+        flags = flags.execute();
+    }
+    flags
+}
+
+/// `mmap(addr, len, prot, flags, path, path_len, offset)`, with the last three in a5-a7.
+/// Returns the address of the mapping, None on failure.
+fn sys_mmap(frame: &TrapFrame) -> Option<u64> {
+    let (addr, len, prot, flags) = (frame.x10, frame.x11, frame.x12, frame.x14);
+    let (path, path_len, offset) = (frame.x15, frame.x16, frame.x17);
+    let kind = if flags & MAP_ANONYMOUS != 0 {
+        VmaKind::Anonymous
+    } else {
+        // Files are only ever mapped read-only, from a page-aligned offset
+        if prot & PROT_WRITE != 0 || offset % PAGE_SIZE as u64 != 0 {
+            return None;
+        }
+        let file = tar::filesystem().lookup(&read_user_str(path, path_len)?)?;
+        if offset as usize > file.data.len() {
+            return None;
+        }
+        VmaKind::File(FileBacking {
+            name: &file.header.name,
+            data: file.data,
+            offset: offset as usize,
+        })
+    };
+    let fixed = (flags & MAP_FIXED != 0).then_some(Vaddr(addr));
+    // Only borrowed now that reading the path, which goes through the address space too, is done
+    process::current_process()
+        .address_space()
+        .mmap(fixed, len, prot_flags(prot), kind)
+        .map(Vaddr::as_number)
+}
+
+/// `vmmap(buf, capacity)`: copy up to `capacity` of the running process's mappings into `buf`.
+/// Returns how many mappings there are in total, None if `buf` can't be written.
+fn sys_vmmap(buf: u64, capacity: u64) -> Option<u64> {
+    let maps = process::current_process().address_space().mappings(false);
+    let count = maps.len().min(capacity as usize);
+    uaccess::copy_to_user(buf, as_bytes(&maps[..count])).ok()?;
+    Some(maps.len() as u64)
+}
+
+/// `meminfo(info, procs, capacity)`: write system-wide memory usage to `info`,
+/// and the usage of up to `capacity` processes to `procs`.
+/// Returns how many processes there are in total, None if the buffers can't be written.
+fn sys_meminfo(info: u64, procs: u64, capacity: u64) -> Option<u64> {
+    uaccess::copy_to_user(info, as_bytes(&[memory::mem_info()])).ok()?;
+    let usage = process::mem_usage();
+    let count = usage.len().min(capacity as usize);
+    uaccess::copy_to_user(procs, as_bytes(&usage[..count])).ok()?;
+    Some(usage.len() as u64)
+}
+
+/// View plain `#[repr(C)]` structs as the bytes to copy out to a process
+fn as_bytes<T: Copy>(items: &[T]) -> &[u8] {
+    unsafe { core::slice::from_raw_parts(items.as_ptr() as *const u8, size_of_val(items)) }
+}
+
+/// Set in `sstatus` when the trap came from supervisor mode
+const SSTATUS_SPP: u64 = 1 << 8;
+
+/// Fill in a page the faulting process is allowed to access,
+/// or kill it if the access was a genuine bad one.
+/// Page faults in the kernel itself are bugs, and are reported as `msg`.
+fn handle_page_fault(
+    msg: &'static str,
+    access: Access,
+    user_pc: u64,
+    stval: u64,
+) -> Result<(), &'static str> {
+    if read_csr!("sstatus") & SSTATUS_SPP != 0 {
+        // We're back at the top of the overflowed stack by now, which is enough to panic on
+        if let Some(slot) = KernelStack::guard_slot(stval) {
+            panic!("Kernel stack overflow in process {slot} at {user_pc:#x} (stval={stval:#x})");
+        }
+        return Err(msg);
+    }
+    let proc = process::current_process();
+    if let Err(err) = proc.handle_page_fault(Vaddr(stval), access) {
+        println!("Process {}: {err} at pc {user_pc:#x}, killed", proc.pid());
+        // Only on request, as it's long and gives away where everything was placed
+        if fdt::boot_info().has_arg("dumpfaults") {
+            proc.address_space().dump();
+        }
+        proc.exit();
+        do_yield();
+        unreachable!("Killed process returned too!");
+    }
+    // sepc still points at the faulting instruction, which gets retried
+    Ok(())
+}
+
 // #[unsafe(link_section = ".text.stvec")]
 #[unsafe(no_mangle)]
 extern "C" fn trap_handler(frame: *mut TrapFrame) {
@@ -204,9 +416,9 @@ extern "C" fn trap_handler(frame: *mut TrapFrame) {
         9 => Err("environment call from HS-mode"),
         10 => Err("environment call from VS-mode"),
         11 => Err("environment call from M-mode"),
-        12 => Err("instruction page fault"),
-        13 => Err("load page fault"),
-        15 => Err("store/AMO page fault"),
+        12 => handle_page_fault("instruction page fault", Access::Execute, sepc, stval),
+        13 => handle_page_fault("load page fault", Access::Read, sepc, stval),
+        15 => handle_page_fault("store/AMO page fault", Access::Write, sepc, stval),
         20 => Err("instruction guest-page fault"),
         21 => Err("load guest-page fault"),
         22 => Err("virtual instruction"),
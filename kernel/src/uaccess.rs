@@ -0,0 +1,72 @@
+use core::arch::asm;
+
+use crate::memory::{PAGE_SIZE, Vaddr};
+use crate::process;
+use crate::vma::{Access, FaultError};
+
+/// Set in `sstatus` to let supervisor mode access pages mapped with the U bit
+const SSTATUS_SUM: u64 = 1 << 18;
+
+/// Copy `dst.len()` bytes from the running process's memory at `src`.
+/// The whole range is checked first, so a bad pointer is an error rather than a kernel page fault.
+pub fn copy_from_user(dst: &mut [u8], src: u64) -> Result<(), FaultError> {
+    prepare(src, dst.len(), Access::Read)?;
+    for_each_page(src, dst.len(), Access::Read, |offset, len| unsafe {
+        let from = (src + offset as u64) as *const u8;
+        core::ptr::copy_nonoverlapping(from, dst.as_mut_ptr().add(offset), len);
+    })
+}
+
+/// Copy `src` into the running process's memory at `dst`.
+/// The whole range is checked first, so a bad pointer is an error rather than a kernel page fault.
+pub fn copy_to_user(dst: u64, src: &[u8]) -> Result<(), FaultError> {
+    prepare(dst, src.len(), Access::Write)?;
+    for_each_page(dst, src.len(), Access::Write, |offset, len| unsafe {
+        let to = (dst + offset as u64) as *mut u8;
+        core::ptr::copy_nonoverlapping(src.as_ptr().add(offset), to, len);
+    })
+}
+
+/// Check every page of `len` bytes from `start` is user memory that allows `access`,
+/// bringing in pages that aren't there yet
+fn prepare(start: u64, len: usize, access: Access) -> Result<(), FaultError> {
+    if len == 0 {
+        return Ok(());
+    }
+    let end = start
+        .checked_add(len as u64)
+        .ok_or(FaultError::Unmapped(start))?;
+    let proc = process::current_process();
+    for page in (start & !(PAGE_SIZE as u64 - 1)..end).step_by(PAGE_SIZE) {
+        proc.fault_in(Vaddr(page), access)?;
+    }
+    Ok(())
+}
+
+/// Call `f` with the offset and length of each page-sized piece of `len` bytes from `start`,
+/// with access to user pages enabled. Each page is faulted in again right before it's touched,
+/// as making room for a later page may have swapped out an earlier one.
+fn for_each_page(
+    start: u64,
+    len: usize,
+    access: Access,
+    mut f: impl FnMut(usize, usize),
+) -> Result<(), FaultError> {
+    let proc = process::current_process();
+    let mut offset = 0;
+    while offset < len {
+        let addr = start + offset as u64;
+        let chunk = (PAGE_SIZE - addr as usize % PAGE_SIZE).min(len - offset);
+        proc.fault_in(Vaddr(addr & !(PAGE_SIZE as u64 - 1)), access)?;
+        with_user_access(|| f(offset, chunk));
+        offset += chunk;
+    }
+    Ok(())
+}
+
+/// Run `f` with access to user pages enabled
+fn with_user_access(f: impl FnOnce()) {
+    unsafe { asm!("csrs sstatus, {}", in(reg) SSTATUS_SUM) };
+    f();
+    unsafe { asm!("csrc sstatus, {}", in(reg) SSTATUS_SUM) };
+}
@@ -6,18 +6,20 @@ use core::{
     sync::atomic::{Ordering, fence},
 };
 
-use alloc::boxed::Box;
-
 use crate::{
     memory::{PAGE_SIZE, align_up, alloc_pages},
     println,
+    slab::SlabCache,
 };
 
 pub const SECTOR_SIZE: u64 = 512;
 
 const VIRTQ_ENTRY_NUM: usize = 16;
 const VIRTIO_DEVICE_BLK: u32 = 2;
+/// The disk holding the filesystem, in the first virtio-mmio slot of the `virt` machine
 pub const VIRTIO_BLK_PADDR: u64 = 0x10001000;
+/// The disk used for swap, if any, in the second virtio-mmio slot
+pub const VIRTIO_SWAP_PADDR: u64 = 0x10002000;
 const VIRTIO_REG_MAGIC: usize = 0x00;
 const VIRTIO_REG_VERSION: usize = 0x04;
 const VIRTIO_REG_DEVICE_ID: usize = 0x08;
@@ -86,20 +88,21 @@ pub struct Virtq {
 }
 
 impl Virtq {
-    pub fn init(index: u32) -> Pin<Box<Self>> {
-        // Can't use `Box::new()` here as we need it to be page-aligned
+    pub fn init(base: u64, index: u32) -> Pin<&'static mut Self> {
+        // Can't use `Box::new()` here as we need it to be page-aligned,
+        // so the queue comes straight from the frame allocator and lives for the rest of the kernel
         let virtq_paddr = alloc_pages(align_up(size_of::<Virtq>(), PAGE_SIZE) / PAGE_SIZE);
-        let mut ptr = unsafe { Box::from_raw(virtq_paddr as *mut Virtq) };
+        let ptr = unsafe { &mut *(virtq_paddr as *mut Virtq) };
         (*ptr).queue_index = index;
         (*ptr).used_index = addr_of_mut!((*ptr).used.index);
         unsafe {
-            virtio_reg_write32(VIRTIO_REG_QUEUE_SEL, index);
-            virtio_reg_write32(VIRTIO_REG_QUEUE_NUM, VIRTQ_ENTRY_NUM as u32);
-            virtio_reg_write32(VIRTIO_REG_QUEUE_ALIGN, 0);
+            virtio_reg_write32(base, VIRTIO_REG_QUEUE_SEL, index);
+            virtio_reg_write32(base, VIRTIO_REG_QUEUE_NUM, VIRTQ_ENTRY_NUM as u32);
+            virtio_reg_write32(base, VIRTIO_REG_QUEUE_ALIGN, 0);
             // Write the physical address of the virtq to
-            virtio_reg_write64(VIRTIO_REG_QUEUE_PFN, addr_of!(*ptr).addr() as u64);
+            virtio_reg_write64(base, VIRTIO_REG_QUEUE_PFN, addr_of!(*ptr).addr() as u64);
         }
-        Box::into_pin(ptr)
+        unsafe { Pin::new_unchecked(ptr) }
     }
 }
 
@@ -119,6 +122,10 @@ impl BlockRequest {
     }
 }
 
+/// Requests are allocated per I/O (unless the driver reserved one) and handed to the device by
+/// physical address
+static BLOCK_REQUESTS: SlabCache<BlockRequest> = SlabCache::new("block_request");
+
 impl Default for BlockRequest {
     fn default() -> Self {
         Self {
@@ -131,37 +138,37 @@ impl Default for BlockRequest {
     }
 }
 
-unsafe fn virtio_reg_read32(offset: usize) -> u32 {
+unsafe fn virtio_reg_read32(base: u64, offset: usize) -> u32 {
     unsafe {
-        let addr = (VIRTIO_BLK_PADDR as *mut u8).add(offset) as *mut u32;
+        let addr = (base as *mut u8).add(offset) as *mut u32;
         addr.read_volatile()
     }
 }
 
-unsafe fn virtio_reg_write32(offset: usize, value: u32) {
+unsafe fn virtio_reg_write32(base: u64, offset: usize, value: u32) {
     unsafe {
-        let addr = (VIRTIO_BLK_PADDR as *mut u8).add(offset) as *mut u32;
+        let addr = (base as *mut u8).add(offset) as *mut u32;
         addr.write_volatile(value);
     }
 }
 
-unsafe fn virtio_reg_read64(offset: usize) -> u64 {
+unsafe fn virtio_reg_read64(base: u64, offset: usize) -> u64 {
     unsafe {
-        let addr = (VIRTIO_BLK_PADDR as *mut u8).add(offset) as *mut u64;
+        let addr = (base as *mut u8).add(offset) as *mut u64;
         addr.read_volatile()
     }
 }
 
-unsafe fn virtio_reg_write64(offset: usize, value: u64) {
+unsafe fn virtio_reg_write64(base: u64, offset: usize, value: u64) {
     unsafe {
-        let addr = (VIRTIO_BLK_PADDR as *mut u8).add(offset) as *mut u64;
+        let addr = (base as *mut u8).add(offset) as *mut u64;
         addr.write_volatile(value);
     }
 }
 
-unsafe fn virtio_reg_fetch_and_or32(offset: usize, value: u32) {
+unsafe fn virtio_reg_fetch_and_or32(base: u64, offset: usize, value: u32) {
     unsafe {
-        let addr = (VIRTIO_BLK_PADDR as *mut u8).add(offset) as *mut u32;
+        let addr = (base as *mut u8).add(offset) as *mut u32;
         addr.write_volatile(addr.read_volatile() | value);
     }
 }
@@ -198,50 +205,95 @@ impl core::fmt::Display for IOError {
 impl core::error::Error for IOError {}
 
 pub struct BlockDeviceDriver {
-    pub virtq: Pin<Box<Virtq>>,
+    /// Physical address of the device's registers
+    base: u64,
+    pub virtq: Pin<&'static mut Virtq>,
     pub capacity: u64,
+    /// A request every transfer reuses, instead of allocating its own. See `reserve_request`.
+    reserved: Option<*mut BlockRequest>,
 }
 
 impl BlockDeviceDriver {
-    /// Initialize the virtual block device
-    pub fn new() -> Self {
+    /// Is there a virtio block device with registers at `base`?
+    /// Slots of the `virt` machine without a device attached report a device id of 0.
+    pub fn is_present(base: u64) -> bool {
         unsafe {
-            match virtio_reg_read32(VIRTIO_REG_MAGIC) {
+            virtio_reg_read32(base, VIRTIO_REG_MAGIC) == 0x74726976
+                && virtio_reg_read32(base, VIRTIO_REG_VERSION) == 1
+                && virtio_reg_read32(base, VIRTIO_REG_DEVICE_ID) == VIRTIO_DEVICE_BLK
+        }
+    }
+
+    /// Initialize the virtual block device with registers at `base`
+    pub fn new(base: u64) -> Self {
+        unsafe {
+            match virtio_reg_read32(base, VIRTIO_REG_MAGIC) {
                 0x74726976 => (),
                 magic => panic!("virtio: Invalid magic, got {:#x}", magic),
             };
-            match virtio_reg_read32(VIRTIO_REG_VERSION) {
+            match virtio_reg_read32(base, VIRTIO_REG_VERSION) {
                 1 => (),
                 version => panic!("virtio: Invalid version, got {version}"),
             };
-            match virtio_reg_read32(VIRTIO_REG_DEVICE_ID) {
+            match virtio_reg_read32(base, VIRTIO_REG_DEVICE_ID) {
                 VIRTIO_DEVICE_BLK => (),
                 other => panic!("virtio: Invalid device: {other}"),
             }
             println!("virtio: Sanity checks passed!");
 
             // Reset the device
-            virtio_reg_write32(VIRTIO_REG_DEVICE_STATUS, 0);
+            virtio_reg_write32(base, VIRTIO_REG_DEVICE_STATUS, 0);
             // Set the ACKNOWLEDGE status bit
-            virtio_reg_fetch_and_or32(VIRTIO_REG_DEVICE_STATUS, VIRTIO_STATUS_ACK);
+            virtio_reg_fetch_and_or32(base, VIRTIO_REG_DEVICE_STATUS, VIRTIO_STATUS_ACK);
             // Set the DRIVER status bit
-            virtio_reg_fetch_and_or32(VIRTIO_REG_DEVICE_STATUS, VIRTIO_STATUS_DRIVER);
+            virtio_reg_fetch_and_or32(base, VIRTIO_REG_DEVICE_STATUS, VIRTIO_STATUS_DRIVER);
             // Set the FEATURES_OK status bit.
             // (Nominally, we should scan the offered features and make sure we can handle them, but 🤷‍♂️)
-            virtio_reg_fetch_and_or32(VIRTIO_REG_DEVICE_STATUS, VIRTIO_STATUS_FEAT_OK);
+            virtio_reg_fetch_and_or32(base, VIRTIO_REG_DEVICE_STATUS, VIRTIO_STATUS_FEAT_OK);
         }
 
         // let virtq = virtq_init(0);
-        let virtq = Virtq::init(0);
+        let virtq = Virtq::init(base, 0);
         let capacity;
 
         unsafe {
-            virtio_reg_write32(VIRTIO_REG_DEVICE_STATUS, VIRTIO_STATUS_DRIVER_OK);
+            virtio_reg_write32(base, VIRTIO_REG_DEVICE_STATUS, VIRTIO_STATUS_DRIVER_OK);
             // Get the disk capacity
-            capacity = virtio_reg_read64(VIRTIO_REG_DEVICE_CONFIG + 0) * SECTOR_SIZE;
+            capacity = virtio_reg_read64(base, VIRTIO_REG_DEVICE_CONFIG + 0) * SECTOR_SIZE;
+        }
+        println!("virtio-blk at {base:#x}: capacity is {capacity} bytes");
+        Self {
+            base,
+            capacity,
+            virtq,
+            reserved: None,
+        }
+    }
+
+    /// Set aside a request for every transfer from now on to reuse, so that reading and writing
+    /// never allocate. Swapping relies on this, as it does its I/O exactly when memory runs out.
+    pub fn reserve_request(&mut self) {
+        if self.reserved.is_none() {
+            self.reserved = Some(BLOCK_REQUESTS.alloc(BlockRequest::default()));
+        }
+    }
+
+    /// A blank request for the next transfer
+    fn request(&mut self) -> *mut BlockRequest {
+        match self.reserved {
+            Some(request) => {
+                unsafe { request.write(BlockRequest::default()) };
+                request
+            }
+            None => BLOCK_REQUESTS.alloc(BlockRequest::default()),
+        }
+    }
+
+    /// Done with `request` from `request`, which is freed unless it's the reserved one
+    fn release(&mut self, request: *mut BlockRequest) {
+        if self.reserved != Some(request) {
+            unsafe { BLOCK_REQUESTS.free(request) };
         }
-        println!("virtio-blk: capacity is {capacity} bytes");
-        Self { capacity, virtq }
     }
 
     /// SAFETY: DO NOT CALL std::mem::swap() on this pointer
@@ -256,7 +308,11 @@ impl BlockDeviceDriver {
         self.vq().available.index += 1;
         fence(Ordering::SeqCst);
         unsafe {
-            virtio_reg_write32(VIRTIO_REG_QUEUE_NOTIFY, (*self.virtq).queue_index);
+            virtio_reg_write32(
+                self.base,
+                VIRTIO_REG_QUEUE_NOTIFY,
+                (*self.virtq).queue_index,
+            );
         }
         self.vq().last_used_index += 1;
     }
@@ -271,10 +327,10 @@ impl BlockDeviceDriver {
             return Err(IOError::NotEnoughSpaceForRead(buf.len()));
         }
         self.assert_sector_in_range(sector)?;
-        let mut request = BlockRequest::default();
+        let request = unsafe { &mut *self.request() };
         request.sector = sector;
         request.type_ = VIRTIO_BLK_T_IN;
-        let address = addr_of!(request).addr();
+        let address = addr_of!(*request).addr();
         self.vq().descriptors[0].addr = address as u64;
         self.vq().descriptors[0].len = (size_of::<u32>() * 2 + size_of::<u64>()) as u32;
         self.vq().descriptors[0].flags = VIRTQ_DESC_F_NEXT;
@@ -291,17 +347,20 @@ impl BlockDeviceDriver {
 
         self.kick(0);
         while self.is_busy() {}
-        if !request.ok() {
-            return Err(IOError::ReadFail(sector, request.status));
-        }
-        unsafe {
-            copy_nonoverlapping(
-                request.data.as_ptr(),
-                buf.as_mut_ptr(),
-                SECTOR_SIZE as usize,
-            );
-        }
-        Ok(())
+        let result = if request.ok() {
+            unsafe {
+                copy_nonoverlapping(
+                    request.data.as_ptr(),
+                    buf.as_mut_ptr(),
+                    SECTOR_SIZE as usize,
+                );
+            }
+            Ok(())
+        } else {
+            Err(IOError::ReadFail(sector, request.status))
+        };
+        self.release(request);
+        result
     }
 
     fn assert_sector_in_range(&self, sector: u64) -> Result<(), IOError> {
@@ -314,13 +373,13 @@ impl BlockDeviceDriver {
 
     pub fn disk_write(&mut self, buf: &[u8], sector: u64) -> Result<(), IOError> {
         self.assert_sector_in_range(sector)?;
-        let mut request = BlockRequest::default();
+        let request = unsafe { &mut *self.request() };
         request.sector = sector;
         request.type_ = VIRTIO_BLK_T_OUT;
         unsafe {
             copy_nonoverlapping(buf.as_ptr(), request.data.as_mut_ptr(), buf.len());
         }
-        let address = addr_of!(request).addr();
+        let address = addr_of!(*request).addr();
         self.vq().descriptors[0].addr = address as u64;
         self.vq().descriptors[0].len = (size_of::<u32>() * 2 + size_of::<u64>()) as u32;
         self.vq().descriptors[0].flags = VIRTQ_DESC_F_NEXT;
@@ -338,10 +397,12 @@ impl BlockDeviceDriver {
         self.kick(0);
         while self.is_busy() {}
 
-        if request.ok() {
+        let result = if request.ok() {
             Ok(())
         } else {
             Err(IOError::WriteFail(sector, request.status))
-        }
+        };
+        self.release(request);
+        result
     }
 }
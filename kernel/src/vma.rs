@@ -0,0 +1,131 @@
+use crate::memory::{PAGE_SIZE, PageFlags};
+
+/// What backs the memory of a region
+#[derive(Debug, Clone, Copy)]
+pub enum VmaKind {
+    /// The program image, which is mapped up front when the process is created
+    Image,
+    /// The heap right after the image, grown and shrunk with `sbrk`. Filled in like `Anonymous`
+    Heap,
+    /// The user stack, a little below `USER_STACK_TOP`. Filled in like `Anonymous`
+    Stack,
+    /// Zero-filled memory, allocated a page at a time as it's first touched
+    Anonymous,
+    /// A copy of part of a file, read in a page at a time as it's first touched
+    File(FileBacking),
+    /// A shared memory object, which is mapped in full up front
+    Shared,
+}
+
+/// The file contents behind a file-backed area
+#[derive(Clone, Copy)]
+pub struct FileBacking {
+    pub name: &'static str,
+    pub data: &'static [u8],
+    /// Offset into `data` of the start of the area
+    pub offset: usize,
+}
+
+impl core::fmt::Debug for FileBacking {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{}+{:#x}", self.name, self.offset)
+    }
+}
+
+/// A virtual memory area: a page-aligned range of a process's address space
+/// that it's allowed to access, and how to fill it in
+#[derive(Debug, Clone, Copy)]
+pub struct Vma {
+    pub start: u64,
+    pub end: u64,
+    pub flags: PageFlags,
+    pub kind: VmaKind,
+}
+
+impl Vma {
+    pub fn new(start: u64, end: u64, flags: PageFlags, kind: VmaKind) -> Self {
+        assert!(
+            start as usize % PAGE_SIZE == 0 && end as usize % PAGE_SIZE == 0,
+            "VMA not page-aligned"
+        );
+        assert!(start < end, "Empty VMA");
+        Self {
+            start,
+            end,
+            flags,
+            kind,
+        }
+    }
+
+    pub fn contains(&self, vaddr: u64) -> bool {
+        self.start <= vaddr && vaddr < self.end
+    }
+
+    pub fn overlaps(&self, start: u64, end: u64) -> bool {
+        self.start < end && start < self.end
+    }
+
+    /// Split the area in two at `at`, which must lie strictly inside it
+    pub fn split_at(self, at: u64) -> (Vma, Vma) {
+        assert!(
+            self.start < at && at < self.end,
+            "Splitting VMA outside of it"
+        );
+        let tail_kind = match self.kind {
+            VmaKind::File(file) => VmaKind::File(FileBacking {
+                offset: file.offset + (at - self.start) as usize,
+                ..file
+            }),
+            kind => kind,
+        };
+        (
+            Vma::new(self.start, at, self.flags, self.kind),
+            Vma::new(at, self.end, self.flags, tail_kind),
+        )
+    }
+
+    /// Does this area allow the given kind of access
+    pub fn allows(&self, access: Access) -> bool {
+        match access {
+            Access::Read => self.flags.can_read(),
+            Access::Write => self.flags.can_write(),
+            Access::Execute => self.flags.can_execute(),
+        }
+    }
+}
+
+/// The kind of memory access that caused a page fault
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+    Execute,
+}
+
+/// A page fault that can't be resolved, so the process can't carry on
+#[derive(Debug, Clone)]
+pub enum FaultError {
+    /// The address isn't part of any area
+    Unmapped(u64),
+    /// The area doesn't allow this kind of access
+    PermissionDenied(u64, Access),
+    /// The page was swapped out, and reading it back failed
+    SwapIn(u64),
+    /// There was no memory left to fill in the page with
+    OutOfMemory(u64),
+}
+
+impl core::fmt::Display for FaultError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            FaultError::Unmapped(vaddr) => write!(f, "access to unmapped address {vaddr:#x}"),
+            FaultError::PermissionDenied(vaddr, access) => {
+                write!(f, "{access:?} access to {vaddr:#x} not permitted")
+            }
+            FaultError::SwapIn(vaddr) => write!(f, "couldn't read {vaddr:#x} back from swap"),
+            FaultError::OutOfMemory(vaddr) => write!(f, "out of memory faulting in {vaddr:#x}"),
+        }
+    }
+}
+
+impl core::error::Error for FaultError {}
@@ -9,6 +9,9 @@ OBJCOPY=/opt/homebrew/opt/llvm/bin/llvm-objcopy
 
 QEMU=qemu-system-riscv64
 
+# User pages are swapped out to their own disk when memory runs low
+[ -f swap.img ] || dd if=/dev/zero of=swap.img bs=4096 count=8192
+
 
 #$OBJCOPY --set-section-flags .bss=alloc,contents -O binary shell.elf shell.bin
 #$OBJCOPY -Ibinary -Oelf64-littleriscv shell.bin shell.bin.o
@@ -25,5 +28,7 @@ $QEMU -machine virt \
     -serial mon:stdio \
     -drive id=drive0,file=lorem.txt,format=raw,if=none \
     -device virtio-blk-device,drive=drive0,bus=virtio-mmio-bus.0 \
+    -drive id=swap0,file=swap.img,format=raw,if=none \
+    -device virtio-blk-device,drive=swap0,bus=virtio-mmio-bus.1 \
     --no-reboot \
     -kernel ./kernel.elf
@@ -1,10 +1,14 @@
 #![no_std]
 #![no_main]
 
+extern crate alloc;
+
+use alloc::vec;
+use alloc::vec::Vec;
 use core::{arch::naked_asm, panic::PanicInfo};
 use userlib::{
     print, println,
-    syscall::{exit, get_char, put_char},
+    syscall::{Mapping, ProcMemInfo, exit, get_char, meminfo, put_char, vmmap},
 };
 #[panic_handler]
 fn panic(_info: &PanicInfo) -> ! {
@@ -15,8 +19,8 @@ fn panic(_info: &PanicInfo) -> ! {
 #[unsafe(no_mangle)]
 #[unsafe(naked)]
 pub extern "C" fn start() {
+    // The kernel hands us a stack to run on
     naked_asm!(
-        "la sp, __stack_top",
         "call {main}",
         "call {exit}",
         main = sym main,
@@ -27,9 +31,8 @@ pub extern "C" fn start() {
 fn main() {
     loop {
         print!("> ");
-        // let mut buf = Vec::with_capacity(512);
-        let mut buf = [0; 512];
-        for i in 0..buf.len() {
+        let mut buf = Vec::with_capacity(512);
+        loop {
             let c = get_char();
 
             put_char(c);
@@ -37,15 +40,87 @@ fn main() {
                 print!("\n");
                 break;
             } else {
-                buf[i] = c;
+                buf.push(c);
             }
         }
-        if &buf[..5] == b"hello" {
+        if buf.starts_with(b"hello") {
             println!("hello world!");
-        } else if &buf[..4] == b"exit" {
+        } else if buf.starts_with(b"vmmap") {
+            print_mappings();
+        } else if buf.starts_with(b"free") {
+            print_free();
+        } else if buf.starts_with(b"top") {
+            print_top();
+        } else if buf.starts_with(b"exit") {
             exit()
         } else {
             println!("Unknown command",);
         }
     }
 }
+
+/// Print the shell's own memory mappings
+fn print_mappings() {
+    let mut maps = vec![Mapping::default(); 16];
+    loop {
+        let Some(count) = vmmap(&mut maps) else {
+            println!("vmmap failed");
+            return;
+        };
+        if count <= maps.len() {
+            for mapping in &maps[..count] {
+                println!("{mapping}");
+            }
+            return;
+        }
+        // Growing the buffer can map more heap, so ask again until everything fits
+        maps.resize(count, Mapping::default());
+    }
+}
+
+/// Print how much memory is in use across the system, in KiB
+fn print_free() {
+    let Some((info, _)) = meminfo(&mut []) else {
+        println!("meminfo failed");
+        return;
+    };
+    println!("{:>6} {:>10} {:>10} {:>10}", "", "total", "used", "free");
+    let row = |name, total: u64, free: u64| {
+        println!(
+            "{name:>6} {:>10} {:>10} {:>10}",
+            total / 1024,
+            (total - free) / 1024,
+            free / 1024
+        );
+    };
+    row("Mem:", info.total, info.free);
+    row("Swap:", info.swap_total, info.swap_total - info.swap_used);
+    row("Heap:", info.heap_total, info.heap_total - info.heap_used);
+    println!("Page tables: {} KiB", info.page_tables / 1024);
+}
+
+/// Print the memory usage of each process, in KiB
+fn print_top() {
+    let mut procs = Vec::new();
+    let count = loop {
+        let Some((_, count)) = meminfo(&mut procs) else {
+            println!("meminfo failed");
+            return;
+        };
+        if count <= procs.len() {
+            break count;
+        }
+        // Processes can be created while the buffer grows, so ask again until they all fit
+        procs.resize(count, ProcMemInfo::default());
+    };
+    println!("{:>4} {:>10} {:>10} {:>10}", "PID", "VIRT", "RES", "SWAP");
+    for proc in &procs[..count] {
+        println!(
+            "{:>4} {:>10} {:>10} {:>10}",
+            proc.pid,
+            proc.virtual_size / 1024,
+            proc.resident / 1024,
+            proc.swapped / 1024
+        );
+    }
+}
@@ -0,0 +1,87 @@
+use crate::syscall::sbrk;
+use core::alloc::{GlobalAlloc, Layout};
+use core::cell::UnsafeCell;
+use core::ptr;
+
+/// How much to grow the heap by at least, to keep the number of `sbrk` calls down
+const GROW_SIZE: usize = 16 * 1024;
+
+/// A bump allocator on top of `sbrk`.
+/// Memory is only reused when the most recent allocation is freed or resized,
+/// which covers the common case of a buffer that's filled, used and thrown away.
+pub struct SbrkAllocator {
+    state: UnsafeCell<State>,
+}
+
+struct State {
+    /// Start of the free space at the end of the heap
+    top: usize,
+    /// Current end of the heap, 0 until the first allocation
+    end: usize,
+}
+
+// Processes are single threaded
+unsafe impl Sync for SbrkAllocator {}
+
+impl SbrkAllocator {
+    pub const fn new() -> Self {
+        Self {
+            state: UnsafeCell::new(State { top: 0, end: 0 }),
+        }
+    }
+}
+
+unsafe impl GlobalAlloc for SbrkAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let state = unsafe { &mut *self.state.get() };
+        if state.end == 0 {
+            match sbrk(0) {
+                Some(start) => {
+                    state.top = start as usize;
+                    state.end = start as usize;
+                }
+                None => return ptr::null_mut(),
+            }
+        }
+        let start = state.top.next_multiple_of(layout.align());
+        let new_top = start + layout.size();
+        if new_top > state.end {
+            let grow = (new_top - state.end).max(GROW_SIZE);
+            if sbrk(grow as isize).is_none() {
+                return ptr::null_mut();
+            }
+            state.end += grow;
+        }
+        state.top = new_top;
+        start as *mut u8
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let state = unsafe { &mut *self.state.get() };
+        if ptr as usize + layout.size() == state.top {
+            state.top = ptr as usize;
+        }
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let state = unsafe { &mut *self.state.get() };
+        let new_top = ptr as usize + new_size;
+        // The last allocation can grow in place
+        if ptr as usize + layout.size() == state.top && new_top <= state.end {
+            state.top = new_top;
+            return ptr;
+        }
+        let new_layout = unsafe { Layout::from_size_align_unchecked(new_size, layout.align()) };
+        let new = unsafe { self.alloc(new_layout) };
+        if !new.is_null() {
+            unsafe {
+                ptr::copy_nonoverlapping(ptr, new, layout.size().min(new_size));
+                self.dealloc(ptr, layout);
+            }
+        }
+        new
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: SbrkAllocator = SbrkAllocator::new();
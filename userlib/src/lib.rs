@@ -1,7 +1,5 @@
 #![no_std]
 #[macro_use]
 pub mod print;
+pub mod heap;
 pub mod syscall;
-unsafe extern "C" {
-    pub static mut __stack_top: u8;
-}
@@ -1,4 +1,8 @@
 use common::Syscall;
+pub use common::{
+    MAP_ANONYMOUS, MAP_FIXED, MAPPING_COW, MAPPING_EXEC, MAPPING_GLOBAL, MAPPING_READ,
+    MAPPING_USER, MAPPING_WRITE, Mapping, MemInfo, PROT_EXEC, PROT_READ, PROT_WRITE, ProcMemInfo,
+};
 use core::arch::asm;
 
 pub fn put_char(ch: u8) {
@@ -16,6 +20,121 @@ pub fn exit() -> ! {
     unreachable!()
 }
 
+/// Move the end of the heap by `increment` bytes.
+/// Returns the old end of the heap, or None if the kernel refused.
+pub fn sbrk(increment: isize) -> Option<*mut u8> {
+    let old = unsafe { syscall(increment as u64, 0, 0, Syscall::SBRK) };
+    if old == u64::MAX {
+        None
+    } else {
+        Some(old as *mut u8)
+    }
+}
+
+/// Map `len` bytes at `addr` (with `MAP_FIXED`) or wherever the kernel picks.
+/// Anonymous mappings (`MAP_ANONYMOUS`) are zero-filled, otherwise the file at `path` is mapped
+/// read-only from `offset` on. Returns the start of the mapping, or None if the kernel refused.
+pub fn mmap(
+    addr: *mut u8,
+    len: usize,
+    prot: u64,
+    flags: u64,
+    path: &str,
+    offset: usize,
+) -> Option<*mut u8> {
+    let args = [
+        addr as u64,
+        len as u64,
+        prot,
+        flags,
+        path.as_ptr() as u64,
+        path.len() as u64,
+        offset as u64,
+    ];
+    let start = unsafe { syscall7(args, Syscall::MMAP) };
+    if start == u64::MAX {
+        None
+    } else {
+        Some(start as *mut u8)
+    }
+}
+
+/// Unmap `len` bytes from `addr`, which must be page-aligned. Returns false if the kernel refused.
+pub fn munmap(addr: *mut u8, len: usize) -> bool {
+    (unsafe { syscall(addr as u64, len as u64, 0, Syscall::MUNMAP) }) == 0
+}
+
+/// Change the protection of `len` bytes from `addr`. Returns false if the kernel refused.
+pub fn mprotect(addr: *mut u8, len: usize, prot: u64) -> bool {
+    (unsafe { syscall(addr as u64, len as u64, prot, Syscall::MPROTECT) }) == 0
+}
+
+/// Open the shared memory object called `name`, creating it with `len` zeroed bytes if it doesn't exist.
+/// Returns a handle any process can map it with, or None if the kernel refused.
+pub fn shm_open(name: &str, len: usize) -> Option<u64> {
+    let id = unsafe {
+        syscall(
+            name.as_ptr() as u64,
+            name.len() as u64,
+            len as u64,
+            Syscall::SHMOPEN,
+        )
+    };
+    (id != u64::MAX).then_some(id)
+}
+
+/// Map all of shared memory object `id`. Returns the start of the mapping, or None if the kernel refused.
+pub fn shm_map(id: u64, prot: u64) -> Option<*mut u8> {
+    let start = unsafe { syscall(id, prot, 0, Syscall::SHMMAP) };
+    (start != u64::MAX).then_some(start as *mut u8)
+}
+
+/// Unmap the shared memory mapped at `addr`. Returns false if the kernel refused.
+pub fn shm_unmap(addr: *mut u8) -> bool {
+    (unsafe { syscall(addr as u64, 0, 0, Syscall::SHMUNMAP) }) == 0
+}
+
+/// Destroy shared memory object `id`. Mappings of it stay valid until they're unmapped.
+pub fn shm_destroy(id: u64) -> bool {
+    (unsafe { syscall(id, 0, 0, Syscall::SHMDESTROY) }) == 0
+}
+
+/// Create a copy of this process. Returns the child's pid in the parent and 0 in the child,
+/// or None if the kernel couldn't create it.
+pub fn fork() -> Option<u64> {
+    let pid = unsafe { syscall(0, 0, 0, Syscall::FORK) };
+    (pid != u64::MAX).then_some(pid)
+}
+
+/// Fill `maps` with the mappings of this process's address space, lowest first.
+/// Returns how many there are in total, which may be more than fit in `maps`.
+pub fn vmmap(maps: &mut [Mapping]) -> Option<usize> {
+    let count = unsafe {
+        syscall(
+            maps.as_mut_ptr() as u64,
+            maps.len() as u64,
+            0,
+            Syscall::VMMAP,
+        )
+    };
+    (count != u64::MAX).then_some(count as usize)
+}
+
+/// Get the memory usage of the whole system, and fill `procs` with that of each process.
+/// Also returns how many processes there are in total, which may be more than fit in `procs`.
+pub fn meminfo(procs: &mut [ProcMemInfo]) -> Option<(MemInfo, usize)> {
+    let mut info = MemInfo::default();
+    let count = unsafe {
+        syscall(
+            &mut info as *mut MemInfo as u64,
+            procs.as_mut_ptr() as u64,
+            procs.len() as u64,
+            Syscall::MEMINFO,
+        )
+    };
+    (count != u64::MAX).then_some((info, count as usize))
+}
+
 unsafe fn syscall(arg0: u64, arg1: u64, arg2: u64, sysno: Syscall) -> u64 {
     let result: u64;
     let sysno: u64 = sysno.into();
@@ -30,3 +149,24 @@ unsafe fn syscall(arg0: u64, arg1: u64, arg2: u64, sysno: Syscall) -> u64 {
     }
     result
 }
+
+/// A syscall with more arguments than fit in a0-a2.
+/// The syscall number stays in a3, so the rest go in a4-a7.
+unsafe fn syscall7(args: [u64; 7], sysno: Syscall) -> u64 {
+    let result: u64;
+    let sysno: u64 = sysno.into();
+    unsafe {
+        asm!(
+            "ecall",
+            inout("a0") args[0] => result,
+            in("a1") args[1],
+            in("a2") args[2],
+            in("a3") sysno,
+            in("a4") args[3],
+            in("a5") args[4],
+            in("a6") args[5],
+            in("a7") args[6],
+        );
+    }
+    result
+}